@@ -6,13 +6,29 @@ serverPort = 8000
 
 DATA = {
     "peers": [
-        {"pubkey": "mmFPYMNnhYmWF7OVW7Zvxfll95mQ634JFZNznrljonA=", "endpoint": "10.32.0.1", "port": 40000, "ip": "10.85.0.34"},
-        {"pubkey": "9jWWDhS9Fr4hHXHQNTnQIAIpLIrcBNy4yA2aXbG1jzw=", "endpoint": "10.32.0.2", "port": 40000, "ip": "10.85.0.35"},
+        {"pubkey": "mmFPYMNnhYmWF7OVW7Zvxfll95mQ634JFZNznrljonA=", "endpoint": "10.32.0.1", "port": 40000, "ip": ["10.85.0.34"]},
+        {"pubkey": "9jWWDhS9Fr4hHXHQNTnQIAIpLIrcBNy4yA2aXbG1jzw=", "endpoint": "10.32.0.2", "port": 40000, "ip": ["10.85.0.35"]},
     ]
 }
 
 class MyServer(BaseHTTPRequestHandler):
     def do_POST(self):
+        length = int(self.headers.get("Content-Length", 0))
+        body = json.loads(self.rfile.read(length) or "null")
+        print("Signature {} (timestamp {}, nonce {})".format(
+            self.headers.get("X-Autopeer-Signature"),
+            self.headers.get("X-Autopeer-Timestamp"),
+            self.headers.get("X-Autopeer-Nonce"),
+        ))
+
+        if self.path == "/peering-report":
+            for report in body:
+                result = "success" if report["success"] else "failed: {}".format(report["reason"])
+                print("Report from {} for peer {} @ {}: {}".format(report["pubkey"], report["peer"], report["endpoint"], result))
+            self.send_response(204)
+            self.end_headers()
+            return
+
         self.send_response(200)
         self.send_header("Content-type", "application/json")
         self.end_headers()
@@ -29,4 +45,4 @@ if __name__ == "__main__":
         pass
 
     webServer.server_close()
-    print("Server stopped.")
\ No newline at end of file
+    print("Server stopped.")
@@ -13,7 +13,7 @@ pub fn autorefresh(tx: Sender<Message>, cancel: CancellationToken, timeout: Time
                 }
                 // 60 seconds timeout, send refresh message
                 _ = tokio::time::sleep(std::time::Duration::from_secs(timeout.into())) => {
-                    let _ = tx.send(Message::RefreshPeers).await.unwrap();
+                    tx.send(Message::RefreshPeers).await.unwrap();
                 }
             }
         }
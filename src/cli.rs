@@ -0,0 +1,198 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use tokio::{select, signal::ctrl_c};
+
+#[cfg(unix)]
+use crate::control::{client::send_request, protocol::{Request, Response}};
+use crate::network::utils::enumerate_networks;
+use crate::state::status::Status;
+use crate::state::structs::{Settings, StateManager};
+
+/// Exit code: all peering queries succeeded
+pub const EXIT_OK: i32 = 0;
+/// Exit code: at least one peering query failed
+pub const EXIT_QUERY_FAILED: i32 = 1;
+/// Exit code: configuration could not be loaded
+pub const EXIT_CONFIG_ERROR: i32 = 2;
+/// Exit code: there is no wireguard interface to query
+pub const EXIT_NO_INTERFACE: i32 = 3;
+/// Exit code: the daemon could not be reached or rejected the command
+pub const EXIT_CONTROL_ERROR: i32 = 4;
+/// Exit code: `sync` was refused because the daemon is running
+pub const EXIT_DAEMON_RUNNING: i32 = 5;
+
+/// Automatic peer to peer connections for WireGuard-Web clients
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Additional configuration file, overrides values from the default locations
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Only log which peers would be added or removed, do not change wireguard interfaces
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Run as daemon with network monitoring and systray (default)
+    Run,
+    /// Synchronize peers without network monitoring and systray
+    Sync {
+        /// Run peering queries once and exit, exit code is non-zero if a query failed
+        #[arg(long)]
+        once: bool,
+    },
+    /// Print interfaces, next hops and managed peers
+    Status,
+    /// Send a command to the running daemon
+    #[cfg(unix)]
+    Ctl {
+        #[command(subcommand)]
+        action: ControlAction,
+    },
+}
+
+#[cfg(unix)]
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum ControlAction {
+    /// Re-run the peering queries
+    Refresh,
+    /// Suspend network monitoring and automatic refresh
+    Suspend,
+    /// Resume network monitoring and automatic refresh
+    Resume,
+    /// Shut down the daemon
+    Quit,
+}
+
+#[cfg(unix)]
+impl From<ControlAction> for Request {
+    fn from(value: ControlAction) -> Self {
+        match value {
+            ControlAction::Refresh => Request::RefreshPeers,
+            ControlAction::Suspend => Request::Suspend,
+            ControlAction::Resume => Request::Resume,
+            ControlAction::Quit => Request::Quit,
+        }
+    }
+}
+
+/// Query peers for all interfaces, repeat every refresh timeout unless `once` is set
+pub async fn sync(settings: Settings, dry_run: bool, once: bool) -> i32 {
+    // the daemon would lose track of the peers and the journal this changes
+    #[cfg(unix)]
+    if send_request(&settings.control_socket_path(), &Request::Status).await.is_ok() {
+        error!("The daemon is running, use `ctl refresh` instead");
+        return EXIT_DAEMON_RUNNING;
+    }
+
+    let mut state = StateManager::new(settings);
+    state.dry_run = dry_run;
+
+    let (performed, failed) = state.load_interfaces(enumerate_networks()).await;
+    if once {
+        if performed == 0 {
+            error!("No wireguard interface found");
+            return EXIT_NO_INTERFACE;
+        }
+        if failed > 0 {
+            return EXIT_QUERY_FAILED;
+        }
+        return EXIT_OK;
+    }
+
+    loop {
+        select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(state.settings.refresh_timeout.into())) => {
+                if let Some(round) = state.check_health().await {
+                    round.send().await;
+                }
+                state.refresh().await;
+            }
+            _ = ctrl_c() => {
+                info!("Received CTRL+C, Shutting down...");
+                break;
+            }
+        }
+    }
+
+    state.shutdown().await;
+    EXIT_OK
+}
+
+/// Print the status of the running daemon, falls back to the local network interfaces
+pub async fn status(settings: Settings) -> i32 {
+    #[cfg(unix)]
+    match send_request(&settings.control_socket_path(), &Request::Status).await {
+        Ok(Response::Status(status)) => {
+            print!("{}", status);
+            return EXIT_OK;
+        }
+        Ok(response) => error!("Unexpected response from daemon: {:?}", response),
+        Err(error) => debug!("Daemon not reachable: {}", error),
+    }
+
+    println!("Daemon is not running, showing local interfaces only");
+    let mut state = StateManager::new(settings);
+    for net in enumerate_networks() {
+        state.ifup(net).await;
+    }
+
+    print!("{}", Status::from(&state));
+    EXIT_OK
+}
+
+/// Send a command to the running daemon
+#[cfg(unix)]
+pub async fn control(settings: Settings, action: ControlAction) -> i32 {
+    match send_request(&settings.control_socket_path(), &action.into()).await {
+        Ok(Response::Ok) => EXIT_OK,
+        Ok(Response::Error { message }) => {
+            error!("Daemon rejected command: {}", message);
+            EXIT_CONTROL_ERROR
+        }
+        Ok(response) => {
+            error!("Unexpected response from daemon: {:?}", response);
+            EXIT_CONTROL_ERROR
+        }
+        Err(error) => {
+            error!("Daemon not reachable: {}", error);
+            EXIT_CONTROL_ERROR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sync_refused_while_daemon_runs() {
+        use tokio::sync::{mpsc, watch};
+        use tokio_util::sync::CancellationToken;
+
+        use crate::cli::{sync, EXIT_DAEMON_RUNNING};
+        use crate::control::server::control_socket;
+        use crate::state::status::Status;
+        use crate::state::structs::Settings;
+
+        let dir = std::env::temp_dir().join(format!("cli-test-{}", std::process::id()));
+        let path = dir.join("control.sock");
+        let (tx, _rx) = mpsc::channel(1);
+        let (_status_tx, status) = watch::channel(Status { suspended: false, dry_run: false, interfaces: vec![], health: vec![] });
+        let cancel = CancellationToken::new();
+        let handle = control_socket(path.clone(), 0o600, tx, status, cancel.clone()).unwrap();
+
+        let settings = Settings { control_socket: Some(path), ..Default::default() };
+        assert_eq!(sync(settings, true, true).await, EXIT_DAEMON_RUNNING);
+
+        cancel.cancel();
+        handle.await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
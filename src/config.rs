@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::state::structs::Settings;
+
+/// System wide configuration directory
+const SYSTEM_CONFIG_DIR: &str = "/etc/wireguard-web-autopeer";
+
+/// Accepted configuration file names, checked in every configuration directory
+const CONFIG_FILE_NAMES: [&str; 2] = ["config.toml", "config.json"];
+
+
+/// Returns all default configuration files that exist, lowest precedence first
+fn default_config_files() -> Vec<PathBuf> {
+    let mut result: Vec<PathBuf> = vec![];
+
+    for name in CONFIG_FILE_NAMES {
+        let path = Path::new(SYSTEM_CONFIG_DIR).join(name);
+        if path.exists() {
+            result.push(path);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Ok(xdg_dir) = xdg::BaseDirectories::with_prefix("wireguard-web-autopeer") {
+        for name in CONFIG_FILE_NAMES {
+            if let Some(path) = xdg_dir.find_config_file(name) {
+                result.push(path);
+            }
+        }
+    }
+
+    result
+}
+
+/// Read a config file into a generic value, the format is selected by the file extension
+fn read_config_file(path: &Path) -> Result<Value, String> {
+    let content = fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str::<Value>(&content).map_err(|error| format!("{}: {}", path.display(), error)),
+        _ => toml::from_str::<Value>(&content).map_err(|error| format!("{}: {}", path.display(), error)),
+    }
+}
+
+/// Merge `overlay` into `base`, tables are merged recursively, everything else is replaced
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(item) => merge(item, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Parse a list of config values, lowest precedence first, into validated settings
+fn settings_from_values(values: Vec<Value>) -> Result<Settings, String> {
+    let mut merged = Value::Object(Default::default());
+    for value in values {
+        merge(&mut merged, value);
+    }
+
+    let settings = serde_json::from_value::<Settings>(merged).map_err(|error| error.to_string())?;
+    settings.validate()?;
+
+    Ok(settings)
+}
+
+/// Load settings from `/etc`, the XDG config dir and an optional explicit config file.
+///
+/// Files later in that list override values of earlier ones, missing default files
+/// are skipped, a missing explicit file is an error.
+pub fn load_settings(explicit: Option<&Path>) -> Result<Settings, String> {
+    let mut files = default_config_files();
+    if let Some(path) = explicit {
+        files.push(path.to_path_buf());
+    }
+
+    let mut values: Vec<Value> = vec![];
+    for file in &files {
+        debug!("Loading config file {}", file.display());
+        values.push(read_config_file(file)?);
+    }
+
+    settings_from_values(values)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::config::{merge, settings_from_values};
+    use crate::state::structs::Settings;
+
+    #[test]
+    fn merge_overrides_nested_values() {
+        let mut base = json!({"a": 1, "b": {"c": 2, "d": 3}});
+        merge(&mut base, json!({"b": {"c": 4}, "e": 5}));
+        assert_eq!(base, json!({"a": 1, "b": {"c": 4, "d": 3}, "e": 5}));
+    }
+
+    #[test]
+    fn settings_precedence() {
+        let system = toml::from_str("refresh_timeout = 60").unwrap();
+        let user = json!({"refresh_timeout": 120});
+        let settings = settings_from_values(vec![system, user]).unwrap();
+        assert_eq!(u64::from(settings.refresh_timeout), 120);
+        assert_eq!(settings_from_values(vec![]).unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn settings_validation() {
+        assert!(settings_from_values(vec![json!({"refresh_timeout": 0})]).is_err());
+        assert!(settings_from_values(vec![json!({"refresh_timeout": "soon"})]).is_err());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let error = settings_from_values(vec![toml::from_str("refresh_timout = 60").unwrap()]).unwrap_err();
+        assert!(error.contains("refresh_timout"), "{}", error);
+        assert!(settings_from_values(vec![json!({"http": {"atempts": 3}})]).is_err());
+        assert!(settings_from_values(vec![json!({"servers": {"wg0": {"dns-server": "10.0.0.1"}}})]).is_err());
+    }
+}
@@ -0,0 +1,24 @@
+use std::path::Path;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use super::protocol::{Request, Response};
+
+
+/// Send one request to the control socket of a running daemon
+pub async fn send_request(path: &Path, request: &Request) -> Result<Response, String> {
+    let stream = UnixStream::connect(path).await.map_err(|error| format!("{}: {}", path.display(), error))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut data = serde_json::to_string(request).map_err(|error| error.to_string())?;
+    data.push('\n');
+    writer.write_all(data.as_bytes()).await.map_err(|error| error.to_string())?;
+
+    let mut lines = BufReader::new(reader).lines();
+    match lines.next_line().await {
+        Ok(Some(line)) => serde_json::from_str::<Response>(&line).map_err(|error| format!("{}: {}", error, line)),
+        Ok(None) => Err("Control socket closed the connection".into()),
+        Err(error) => Err(error.to_string()),
+    }
+}
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+
+use crate::state::{messages::Message, status::Status};
+
+/// Command sent to the control socket, one JSON object per line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    RefreshPeers,
+    Suspend,
+    Resume,
+    Quit,
+    Status,
+}
+
+/// Answer of the control socket, one JSON object per line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Status(Status),
+    Error { message: String },
+}
+
+impl Request {
+    /// Event bus message for this request, `None` if it is answered by the socket itself
+    pub fn message(&self) -> Option<Message> {
+        match self {
+            Request::RefreshPeers => Some(Message::RefreshPeers),
+            Request::Suspend => Some(Message::Suspend),
+            Request::Resume => Some(Message::Resume),
+            Request::Quit => Some(Message::Quit),
+            Request::Status => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::control::protocol::{Request, Response};
+
+    #[test]
+    fn request_format() {
+        assert_eq!(serde_json::from_str::<Request>(r#"{"command": "refresh_peers"}"#).unwrap(), Request::RefreshPeers);
+        assert_eq!(serde_json::to_string(&Request::Quit).unwrap(), r#"{"command":"quit"}"#);
+        assert!(serde_json::from_str::<Request>(r#"{"command": "reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn response_format() {
+        assert_eq!(serde_json::to_string(&Response::Ok).unwrap(), r#"{"result":"ok"}"#);
+        assert_eq!(
+            serde_json::to_string(&Response::Error { message: "nope".into() }).unwrap(),
+            r#"{"result":"error","message":"nope"}"#
+        );
+    }
+}
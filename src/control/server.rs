@@ -0,0 +1,149 @@
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc::Sender, watch};
+use tokio::{select, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::state::{messages::Message, status::Status};
+
+use super::protocol::{Request, Response};
+
+/// Longest request line, longer requests close the connection
+const MAX_REQUEST: u64 = 4096;
+
+/// Bind the control socket, refuses to replace the socket of a running daemon
+fn bind(path: &Path, mode: u32) -> Result<UnixListener, String> {
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(format!("{}: another instance is already listening", path.display()));
+        }
+        debug!("Removing stale control socket {}", path.display());
+        fs::remove_file(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    }
+    if let Some(parent) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(parent).map_err(|error| format!("{}: {}", parent.display(), error))?;
+    }
+
+    // the socket is created with the permissions of the umask, never wider than `mode`
+    let umask = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.map_err(|error| format!("{}: {}", path.display(), error))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|error| format!("{}: {}", path.display(), error))?;
+
+    Ok(listener)
+}
+
+/// Send one response line, false if the client is gone
+async fn respond(writer: &mut OwnedWriteHalf, response: &Response) -> bool {
+    let mut data = serde_json::to_string(response).unwrap();
+    data.push('\n');
+    writer.write_all(data.as_bytes()).await.is_ok()
+}
+
+/// Answer requests of one client until it disconnects
+async fn handle_client(stream: UnixStream, tx: Sender<Message>, status: watch::Receiver<Status>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        match (&mut reader).take(MAX_REQUEST).read_line(&mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if !line.ends_with('\n') && (line.len() as u64 >= MAX_REQUEST) {
+            respond(&mut writer, &Response::Error { message: format!("request longer than {} bytes", MAX_REQUEST) }).await;
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                debug!("Control socket request: {:?}", request);
+                match request.message() {
+                    Some(message) => match tx.send(message).await {
+                        Ok(_) => Response::Ok,
+                        Err(error) => Response::Error { message: error.to_string() },
+                    },
+                    None => Response::Status(status.borrow().clone()),
+                }
+            }
+            Err(error) => Response::Error { message: error.to_string() },
+        };
+
+        if !respond(&mut writer, &response).await {
+            break;
+        }
+    }
+}
+
+/// Run the control socket, forwards commands to the event bus and answers status requests
+pub fn control_socket(path: PathBuf, mode: u32, tx: Sender<Message>, status: watch::Receiver<Status>, cancel: CancellationToken) -> Result<JoinHandle<()>, String> {
+    let listener = bind(&path, mode)?;
+    info!("Listening on control socket {}", path.display());
+
+    Ok(tokio::spawn(async move {
+        loop {
+            select! {
+                // cancelled, break loop, exit task
+                _ = cancel.cancelled() => {
+                    break;
+                }
+                // New client
+                client = listener.accept() => {
+                    match client {
+                        Ok((stream, _)) => {
+                            tokio::spawn(handle_client(stream, tx.clone(), status.clone()));
+                        }
+                        Err(error) => error!("Control socket error: {}", error),
+                    }
+                }
+            }
+        }
+        let _ = fs::remove_file(&path);
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+    use tokio::sync::{mpsc, watch};
+
+    use crate::control::server::{bind, handle_client, MAX_REQUEST};
+    use crate::state::status::Status;
+
+    #[tokio::test]
+    async fn private_socket_and_long_requests() {
+        let dir = std::env::temp_dir().join(format!("control-test-{}", std::process::id()));
+        let path = dir.join("control.sock");
+        let listener = bind(&path, 0o600).unwrap();
+        assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let (tx, _rx) = mpsc::channel(1);
+        let (_status_tx, status) = watch::channel(Status { suspended: false, dry_run: false, interfaces: vec![], health: vec![] });
+        let client = UnixStream::connect(&path).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(handle_client(stream, tx, status));
+
+        let (reader, mut writer) = client.into_split();
+        writer.write_all(&vec![b' '; MAX_REQUEST as usize + 1]).await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        assert!(lines.next_line().await.unwrap().unwrap().contains("longer than"));
+        assert_eq!(lines.next_line().await.unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
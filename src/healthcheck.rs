@@ -0,0 +1,21 @@
+use crate::state::{messages::Message, structs::Timeout};
+use tokio::{sync::mpsc::Sender, task::JoinHandle, select};
+use tokio_util::sync::CancellationToken;
+
+
+pub fn healthcheck(tx: Sender<Message>, cancel: CancellationToken, interval: Timeout) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            select! {
+                // cancelled, break loop, exit task
+                _ = cancel.cancelled() => {
+                    break;
+                }
+                // interval elapsed, check handshakes of all peers
+                _ = tokio::time::sleep(std::time::Duration::from_secs(interval.into())) => {
+                    tx.send(Message::CheckHealth).await.unwrap();
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,173 @@
+use std::fmt;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::state::health::unix_time;
+use crate::state::structs::{HttpSettings, ServerSettings};
+
+use super::discovery::ServerEndpoint;
+use super::tls::client;
+
+
+/// HTTP client for the server of a wireguard interface, kept as long as settings and endpoint
+/// stay the same so connections are reused
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub endpoint: ServerEndpoint,
+    pub client: reqwest::Client,
+    server: ServerSettings,
+    http: HttpSettings,
+}
+
+impl Connection {
+    pub fn new(server: ServerSettings, endpoint: ServerEndpoint, http: &HttpSettings) -> Result<Self, String> {
+        Ok(Self {
+            client: client(&server, &endpoint, http)?,
+            endpoint,
+            server,
+            http: http.clone(),
+        })
+    }
+
+    /// Check if the client was built for these settings
+    pub fn matches(&self, server: &ServerSettings, endpoint: &ServerEndpoint, http: &HttpSettings) -> bool {
+        (&self.server == server) && (&self.endpoint == endpoint) && (&self.http == http)
+    }
+}
+
+// clients can not be compared, a client is equal when it was built for the same settings
+impl PartialEq for Connection {
+    fn eq(&self, other: &Self) -> bool {
+        other.matches(&self.server, &self.endpoint, &self.http)
+    }
+}
+
+/// `base * 2^exponent` capped at `max`, minus a random jitter of up to half the delay so clients
+/// that failed at the same time do not retry at the same time
+pub fn backoff_delay(base: u64, exponent: u32, max: u64) -> u64 {
+    let delay = base.saturating_mul(1u64.checked_shl(exponent).unwrap_or(u64::MAX)).min(max);
+    delay - rand::thread_rng().gen_range(0..=delay / 2)
+}
+
+/// State of the circuit breaker of a server
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Queries are sent
+    #[default]
+    Closed,
+    /// The server failed too often, queries are paused until the retry time
+    Open,
+    /// One trial query is sent after the pause, its outcome closes or opens the circuit again
+    HalfOpen,
+}
+
+/// Pauses queries to a server that keeps failing, so a dead server does not cost a timeout on
+/// every refresh
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CircuitBreaker {
+    pub state: CircuitState,
+    /// Failed queries in a row
+    pub failures: u32,
+    /// Unix timestamp of the next trial query while the circuit is open
+    pub retry_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl CircuitBreaker {
+    /// Check if a query may be sent, an open circuit lets one trial query through after the pause
+    pub fn allow(&mut self, now: u64) -> bool {
+        match self.state {
+            CircuitState::Closed | CircuitState::HalfOpen => true,
+            CircuitState::Open => {
+                if self.retry_at.is_none_or(|retry_at| now >= retry_at) {
+                    self.state = CircuitState::HalfOpen;
+                    return true;
+                }
+                false
+            }
+        }
+    }
+
+    pub fn success(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Count a failed query, opens the circuit after `failure_threshold` failures in a row or a
+    /// failed trial, every further failure doubles the pause
+    pub fn failure(&mut self, error: String, now: u64, settings: &HttpSettings) {
+        self.failures += 1;
+        self.last_error = Some(error);
+
+        if (self.state == CircuitState::HalfOpen) || (self.failures >= settings.failure_threshold) {
+            let exponent = self.failures.saturating_sub(settings.failure_threshold);
+            self.state = CircuitState::Open;
+            self.retry_at = Some(now + backoff_delay(settings.backoff, exponent, settings.max_backoff));
+        }
+    }
+}
+
+impl fmt::Display for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.state {
+            CircuitState::Closed => write!(f, "circuit closed")?,
+            CircuitState::Open => write!(f, "circuit open, retry in {} seconds", self.retry_at.unwrap_or_default().saturating_sub(unix_time()))?,
+            CircuitState::HalfOpen => write!(f, "circuit half open")?,
+        }
+        if self.failures > 0 {
+            write!(f, ", {} failures", self.failures)?;
+        }
+        if let Some(error) = &self.last_error {
+            write!(f, ", last error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::http::client::{backoff_delay, CircuitBreaker, CircuitState};
+    use crate::state::structs::HttpSettings;
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        for _ in 0..100 {
+            let delay = backoff_delay(10, 0, 1000);
+            assert!((5..=10).contains(&delay));
+            let delay = backoff_delay(10, 3, 1000);
+            assert!((40..=80).contains(&delay));
+            let delay = backoff_delay(10, 63, 1000);
+            assert!((500..=1000).contains(&delay));
+        }
+    }
+
+    #[test]
+    fn circuit_opens_and_closes() {
+        let settings = HttpSettings { failure_threshold: 2, backoff: 60, max_backoff: 600, ..Default::default() };
+        let mut breaker = CircuitBreaker::default();
+
+        assert!(breaker.allow(1000));
+        breaker.failure("timeout".into(), 1000, &settings);
+        assert_eq!(breaker.state, CircuitState::Closed);
+        breaker.failure("timeout".into(), 1000, &settings);
+        assert_eq!(breaker.state, CircuitState::Open);
+        let retry_at = breaker.retry_at.unwrap();
+        assert!((1030..=1060).contains(&retry_at));
+
+        // paused until the retry time, then one trial
+        assert!(!breaker.allow(retry_at - 1));
+        assert!(breaker.allow(retry_at));
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+
+        // a failed trial doubles the pause
+        breaker.failure("timeout".into(), 2000, &settings);
+        assert_eq!(breaker.state, CircuitState::Open);
+        assert!((2060..=2120).contains(&breaker.retry_at.unwrap()));
+
+        assert!(breaker.allow(3000));
+        breaker.success();
+        assert_eq!(breaker, CircuitBreaker::default());
+    }
+}
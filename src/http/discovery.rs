@@ -0,0 +1,249 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use if_watch::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::network::dns::{query, resolve_host, Record, ResolverConfig, TYPE_SRV, TYPE_TXT};
+use crate::network::utils::FirstIp;
+use crate::state::structs::{NetworkInterface, Scheme, ServerSettings};
+
+/// Service name of the discovery records below the domain
+pub const DISCOVERY_SERVICE: &str = "_wireguard-web._tcp";
+
+
+/// Where the URL of a server came from
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EndpointSource {
+    /// `url` or `host` in the server settings
+    Config,
+    /// `url=` TXT record of the discovery domain
+    Txt,
+    /// SRV record of the discovery domain
+    Srv,
+    /// First ip of the wireguard network
+    FirstIp,
+}
+
+impl fmt::Display for EndpointSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndpointSource::Config => write!(f, "config"),
+            EndpointSource::Txt => write!(f, "dns txt"),
+            EndpointSource::Srv => write!(f, "dns srv"),
+            EndpointSource::FirstIp => write!(f, "first ip"),
+        }
+    }
+}
+
+/// Base URL of the WireGuard-Web server of a wireguard interface
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerEndpoint {
+    /// URL without trailing slash, endpoint paths are appended
+    pub url: String,
+    /// Address of the server, also when the URL contains a host name
+    pub address: Option<IpAddr>,
+    pub source: EndpointSource,
+}
+
+impl fmt::Display for ServerEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.url, self.source)
+    }
+}
+
+
+/// Base URL from the server settings for a host name or ip address
+fn base_url(server: &ServerSettings, host: &str, port: Option<u16>) -> String {
+    let scheme = match server.scheme {
+        Scheme::Http => "http",
+        Scheme::Https => "https",
+    };
+    let host = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+        _ => host.to_string(),
+    };
+    let port = port.or(server.port).map(|port| format!(":{}", port)).unwrap_or_default();
+
+    format!("{}://{}{}{}", scheme, host, port, server.path.trim_end_matches('/'))
+}
+
+/// Endpoint for a URL, host names are resolved with the DNS server if there is one. Without one the
+/// system resolver is asked, but the local network controls it, so only an address inside the tunnel
+/// network is accepted. The address of the server has to be known to keep peers from taking it over.
+async fn endpoint(url: &str, dns_server: Option<IpAddr>, tunnel: Option<IpNet>, source: EndpointSource) -> Result<ServerEndpoint, String> {
+    let parsed = reqwest::Url::parse(url).map_err(|error| format!("{} is not a valid URL: {}", url, error))?;
+    let host = parsed.host_str().ok_or(format!("{} has no host", url))?;
+    let address = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => match (dns_server, tunnel) {
+            (Some(dns_server), _) => resolve_host(dns_server, host).await?.first().copied(),
+            (None, Some(tunnel)) => tokio::net::lookup_host((host, 0))
+                .await
+                .map_err(|error| format!("{}: {}", host, error))?
+                .map(|address| address.ip())
+                .find(|ip| tunnel.contains(ip)),
+            (None, None) => None,
+        }
+        .ok_or(format!("{} has no usable address", host))?,
+    };
+
+    Ok(ServerEndpoint { url: url.trim_end_matches('/').to_string(), address: Some(address), source })
+}
+
+/// Look up the discovery records of a domain, a `url=` TXT record wins over a SRV record
+async fn discover_records(server: &ServerSettings, dns_server: IpAddr, domain: &str) -> Result<Option<ServerEndpoint>, String> {
+    let name = format!("{}.{}", DISCOVERY_SERVICE, domain);
+
+    for record in query(dns_server, &name, TYPE_TXT).await? {
+        if let Record::Txt(text) = record {
+            if let Some(url) = text.strip_prefix("url=") {
+                return endpoint(url, Some(dns_server), None, EndpointSource::Txt).await.map(Some);
+            }
+        }
+    }
+
+    // lowest priority first, highest weight among equal priorities
+    let mut services: Vec<(u16, u16, u16, String)> = query(dns_server, &name, TYPE_SRV)
+        .await?
+        .into_iter()
+        .filter_map(|record| match record {
+            Record::Srv { priority, weight, port, target } => Some((priority, u16::MAX - weight, port, target)),
+            _ => None,
+        })
+        .collect();
+    services.sort();
+
+    match services.first() {
+        Some((_, _, port, target)) => {
+            let url = base_url(server, target, Some(*port));
+            endpoint(&url, Some(dns_server), None, EndpointSource::Srv).await.map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Name server to resolve the server with. The local network controls the system resolver, so its
+/// name server is only trusted when it is inside the tunnel.
+fn trusted_dns_server(server: &ServerSettings, resolver: &ResolverConfig, wg: &NetworkInterface) -> Option<IpAddr> {
+    let in_tunnel = |ip: &IpAddr| wg.net.is_some_and(|net| net.contains(ip));
+    server.dns_server.or(resolver.nameserver.filter(in_tunnel))
+}
+
+/// Name server and domain to look up the discovery records with, discovery only runs when both are
+/// configured or the name server is inside the tunnel
+fn discovery_target(server: &ServerSettings, resolver: &ResolverConfig, wg: &NetworkInterface) -> Option<(IpAddr, String)> {
+    let in_tunnel = |ip: &IpAddr| wg.net.is_some_and(|net| net.contains(ip));
+    // the search domain is only trusted together with a name server of the tunnel, e.g. from wg-quick
+    let resolver_domain = || resolver.domain.clone().filter(|_| resolver.nameserver.is_some_and(|ip| in_tunnel(&ip)));
+
+    match (server.dns_server, &server.domain) {
+        (Some(dns_server), Some(domain)) => Some((dns_server, domain.clone())),
+        (Some(dns_server), None) if in_tunnel(&dns_server) => resolver_domain().map(|domain| (dns_server, domain)),
+        (Some(_), None) => None,
+        (None, domain) => {
+            let dns_server = resolver.nameserver.filter(in_tunnel)?;
+            domain.clone().or_else(resolver_domain).map(|domain| (dns_server, domain))
+        }
+    }
+}
+
+/// Find the server of a wireguard interface, falls back to the first ip of its network. A configured
+/// server that can not be resolved is an error, another server must not be used instead.
+pub async fn discover(server: &ServerSettings, wg: &NetworkInterface) -> Result<ServerEndpoint, String> {
+    let resolver = ResolverConfig::system();
+    let dns_server = trusted_dns_server(server, &resolver, wg);
+
+    let configured = match (&server.url, &server.host) {
+        (Some(url), _) => Some(url.clone()),
+        (None, Some(host)) => Some(base_url(server, host, None)),
+        (None, None) => None,
+    };
+    if let Some(url) = configured {
+        return endpoint(&url, dns_server, wg.net, EndpointSource::Config)
+            .await
+            .map_err(|error| format!("Could not resolve server {}: {}", url, error));
+    }
+
+    match discovery_target(server, &resolver, wg) {
+        Some((dns_server, domain)) => match discover_records(server, dns_server, &domain).await {
+            Ok(Some(endpoint)) => return Ok(endpoint),
+            Ok(None) => debug!("No discovery records for interface {} in {}", wg.name, domain),
+            Err(error) => debug!("Server discovery for interface {} in {} failed: {}", wg.name, domain, error),
+        },
+        None => debug!("No trusted name server and domain for server discovery on interface {}", wg.name),
+    }
+
+    let ip = wg.net.unwrap().first_ip();
+    Ok(ServerEndpoint { url: base_url(server, &ip.to_string(), None), address: Some(ip), source: EndpointSource::FirstIp })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::http::discovery::{base_url, discover, discovery_target, EndpointSource};
+    use crate::network::dns::ResolverConfig;
+    use crate::state::structs::{NetworkInterface, Scheme, ServerSettings};
+    use crate::state::testing::interface;
+
+    fn wg(net: &str) -> NetworkInterface {
+        interface("wg0", net)
+    }
+
+    #[test]
+    fn configured_url() {
+        let server = ServerSettings { scheme: Scheme::Https, port: Some(8443), path: "/api/".into(), ..Default::default() };
+        assert_eq!(base_url(&server, "10.0.0.1", None), "https://10.0.0.1:8443/api");
+        assert_eq!(base_url(&server, "fd00::1", None), "https://[fd00::1]:8443/api");
+        assert_eq!(base_url(&server, "vpn.example.org", Some(443)), "https://vpn.example.org:443/api");
+        assert_eq!(base_url(&ServerSettings::default(), "10.0.0.1", None), "http://10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn explicit_url_wins() {
+        let server = ServerSettings { url: Some("https://10.0.0.254/api/".into()), domain: Some("example.org".into()), ..Default::default() };
+        let endpoint = discover(&server, &wg("10.0.0.2/24")).await.unwrap();
+        assert_eq!(endpoint.url, "https://10.0.0.254/api");
+        assert_eq!(endpoint.address, Some("10.0.0.254".parse().unwrap()));
+        assert_eq!(endpoint.source, EndpointSource::Config);
+    }
+
+    #[tokio::test]
+    async fn configured_host_name_in_tunnel() {
+        // without a name server of the tunnel the system resolver may not point outside of it
+        let server = ServerSettings { host: Some("localhost".into()), ..Default::default() };
+        assert!(discover(&server, &wg("10.0.0.2/24")).await.is_err());
+
+        let endpoint = discover(&server, &wg("127.0.0.2/8")).await.unwrap();
+        assert_eq!(endpoint.url, "http://localhost");
+        assert_eq!(endpoint.address, Some("127.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn first_ip_fallback() {
+        // documentation address as DNS server, discovery fails and the first ip is used
+        let server = ServerSettings { dns_server: Some("192.0.2.1".parse().unwrap()), domain: Some("invalid..name".into()), ..Default::default() };
+        let endpoint = discover(&server, &wg("10.0.0.2/24")).await.unwrap();
+        assert_eq!(endpoint.url, "http://10.0.0.1");
+        assert_eq!(endpoint.source, EndpointSource::FirstIp);
+    }
+
+    #[test]
+    fn untrusted_resolver() {
+        let wg = wg("10.0.0.2/24");
+        let lan = ResolverConfig { nameserver: Some("192.168.1.1".parse().unwrap()), domain: Some("lan".into()) };
+        let tunnel = ResolverConfig { nameserver: Some("10.0.0.1".parse().unwrap()), domain: Some("vpn.example.org".into()) };
+
+        // the name server of the local network must not point to a server
+        assert_eq!(discovery_target(&ServerSettings::default(), &lan, &wg), None);
+        assert_eq!(discovery_target(&ServerSettings { domain: Some("example.org".into()), ..Default::default() }, &lan, &wg), None);
+        assert_eq!(discovery_target(&ServerSettings::default(), &tunnel, &wg), Some(("10.0.0.1".parse().unwrap(), "vpn.example.org".into())));
+
+        let explicit = ServerSettings { dns_server: Some("192.0.2.1".parse().unwrap()), domain: Some("example.org".into()), ..Default::default() };
+        assert_eq!(discovery_target(&explicit, &lan, &wg), Some(("192.0.2.1".parse().unwrap(), "example.org".into())));
+        // a search domain from the local network is not combined with a configured name server
+        let dns_only = ServerSettings { dns_server: Some("192.0.2.1".parse().unwrap()), ..Default::default() };
+        assert_eq!(discovery_target(&dns_only, &lan, &wg), None);
+    }
+}
@@ -1 +1,5 @@
-pub mod peering;
\ No newline at end of file
+pub mod client;
+pub mod discovery;
+pub mod peering;
+pub mod signature;
+pub mod tls;
@@ -1,20 +1,43 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use serde_json;
+use tokio::time::sleep;
 
 use reqwest;
 
-use crate::state::structs::{Peer, StateManager, NetworkInterface};
+use crate::state::health::{HealthRecord, PeerHealth};
+use crate::state::query::QueryContext;
+use crate::state::structs::{Peer, NetworkInterface};
+use crate::network::stun::NatMapping;
 use crate::network::utils::FirstIp;
+use crate::wireguard::backend::blocking;
+use crate::wireguard::information::signing_keys;
 
+use super::client::{backoff_delay, Connection};
+use super::discovery::EndpointSource;
+use super::signature::{signature_headers, signing_key};
+use super::tls::error_chain;
+
+/// One local network, the server matches peers whose networks overlap
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct PeeringRequest {
     ip: IpAddr,
     #[serde(rename = "netmask")]
     prefix_len: u8,
-    gateway: IpAddr,
+    gateway: Option<IpAddr>,
     pubkey: String,
+    /// The network holds the default route
+    default: bool,
+    /// Route metric, lower is better
+    metric: Option<u32>,
+    /// External address of the network as seen by a STUN server, behind a NAT the port is the one of
+    /// the probe and not of wireguard
+    public_address: Option<SocketAddr>,
+    nat: Option<NatMapping>,
+    /// Hash of the gateway MAC address to tell apart networks with the same addresses
+    fingerprint: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -22,59 +45,200 @@ pub struct PeeringResponse {
     pub peers: Vec<Peer>,
 }
 
+/// Outcome of a direct connection to a peer the server proposed
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PeeringReport {
+    pubkey: String,
+    peer: String,
+    endpoint: Option<IpAddr>,
+    success: bool,
+    /// Seconds from adding the peer to the first handshake
+    handshake_latency: Option<u64>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    reason: Option<String>,
+}
+
+impl PeeringReport {
+    pub fn new(pubkey: String, record: &HealthRecord, handshake_timeout: u64) -> Self {
+        Self {
+            pubkey,
+            peer: record.pubkey.clone(),
+            endpoint: record.endpoint,
+            success: record.health == PeerHealth::Connected,
+            handshake_latency: record.handshake.map(|handshake| handshake.saturating_sub(record.added)),
+            rx_bytes: record.rx_bytes.saturating_sub(record.rx_baseline),
+            tx_bytes: record.tx_bytes.saturating_sub(record.tx_baseline),
+            reason: match record.health {
+                PeerHealth::Failed => Some(format!("no handshake within {} seconds", handshake_timeout)),
+                _ => None,
+            },
+        }
+    }
+}
+
+
+/// Post a JSON body to the server of a wireguard interface, signed with the interface key if possible.
+/// Failed connections are retried with a growing delay, timeouts and server errors only if the
+/// request is `idempotent` as the server may have processed it.
+async fn post(context: &QueryContext, wg: &NetworkInterface, connection: &Connection, path: &str, data: String, idempotent: bool) -> Result<reqwest::Response, String> {
+    let url = format!("{}{}", connection.endpoint.url, path);
+    debug!("Sending to {} JSON: {}", url, data);
+
+    let (name, server_ip) = (wg.name.clone(), connection.endpoint.address.unwrap_or(wg.net.unwrap().first_ip()));
+    let key = blocking(&context.backend, move |backend| signing_keys(backend, &name, server_ip))
+        .await
+        .map(|(private_key, server_pubkey)| signing_key(private_key, server_pubkey));
+    check_signature(context, wg, connection, key.is_some())?;
+
+    let http = &context.settings.http;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
 
-pub async fn peering_request(state: &StateManager, wg: &NetworkInterface) -> Result<PeeringResponse, String> {
+        // every attempt gets a fresh nonce
+        let mut request = connection.client.post(&url).header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(key) = &key {
+            for (name, value) in signature_headers(key, path, data.as_bytes()) {
+                request = request.header(name, value);
+            }
+        }
+
+        let result = request.body(data.clone()).send().await;
+        let retry = match &result {
+            Ok(response) => idempotent && response.status().is_server_error(),
+            Err(error) => error.is_connect() || (idempotent && error.is_timeout()),
+        };
+        if !retry || (attempt >= http.attempts) {
+            return result.map_err(|error| format!("Request to {} failed: {}", url, error_chain(&error)));
+        }
+
+        let delay = backoff_delay(http.retry_delay, attempt - 1, http.request_timeout * 1000);
+        debug!("Request to {} failed, retrying in {} ms", url, delay);
+        sleep(Duration::from_millis(delay)).await;
+    }
+}
+
+
+/// Check if a request may be sent with or without a signature. Once a request on an interface was
+/// signed unsigned requests fail, a server found somewhere else does not reset this.
+fn check_signature(context: &QueryContext, wg: &NetworkInterface, connection: &Connection, signed: bool) -> Result<(), String> {
+    let mut interfaces = context.signed.lock().unwrap();
+    if signed {
+        interfaces.insert(wg.name.clone());
+        return Ok(());
+    }
+
+    // a discovered server only gets requests that only the real server can verify
+    if matches!(connection.endpoint.source, EndpointSource::Txt | EndpointSource::Srv) {
+        return Err(format!("No key to sign requests on interface {}, refusing to send unsigned request to discovered server {}", wg.name, connection.endpoint));
+    }
+    let server = context.settings.server(&wg.name);
+    if server.require_signature.unwrap_or_else(|| interfaces.contains(&wg.name)) {
+        return Err(format!("No key to sign requests on interface {}, refusing to send unsigned request to {}", wg.name, connection.endpoint));
+    }
+    warn!("No key to sign requests on interface {}, sending unsigned request", wg.name);
+    Ok(())
+}
+
+pub async fn peering_request(state: &QueryContext, wg: &NetworkInterface, connection: &Connection) -> Result<PeeringResponse, String> {
     let mut json_data: Vec<PeeringRequest> = vec![];
 
     for item in &state.interfaces {
-        if !item.is_default {
+        if !item.is_underlay() || !state.settings.underlay_interfaces.matches(&item.name) {
             continue;
         }
-        if let (Some(gw), Some(net)) = (item.nexthop, item.net) {
+        if let Some(net) = item.net {
+            let public = state.public_addresses.get(&net.addr());
             json_data.push(PeeringRequest{
                 ip: net.addr(),
                 prefix_len: net.prefix_len(),
-                gateway: gw,
-                pubkey: wg.wireguard.clone().unwrap().pubkey.unwrap()
+                gateway: item.nexthop,
+                pubkey: wg.wireguard.clone().unwrap().pubkey.unwrap(),
+                default: item.is_default,
+                metric: item.metric,
+                // without NAT the listen port of wireguard is reachable instead of the port of the probe
+                public_address: public.map(|public| match (public.mapping, &wg.wireguard) {
+                    (NatMapping::None, Some(wireguard)) => SocketAddr::new(public.address.ip(), wireguard.port),
+                    _ => public.address,
+                }),
+                nat: public.map(|public| public.mapping),
+                fingerprint: item.fingerprint.clone(),
             });
         }
     }
-
-    let ip = wg.net.unwrap().first_ip();
+    // best network first
+    json_data.sort_by_key(|item| (!item.default, item.metric.unwrap_or(u32::MAX)));
 
     if let Ok(data) = serde_json::to_string(&json_data) {
-        debug!("Sending to {:?} JSON: {}", ip, data);
-
-        let client = reqwest::Client::new();
-        let url = match ip {
-            IpAddr::V4(_ip) => format!("http://{}/peering-request", ip),
-            IpAddr::V6(_ip) => format!("http://[{}]/peering-request", ip)
-        };
-        let res = client.post(url)
-            .json(&json_data)
-            .send().await;
-        match res {
+        match post(state, wg, connection, "/peering-request", data, true).await {
             Ok(response) => {
                 match response.text().await {
                     Ok(result) => {
                         match serde_json::from_str::<PeeringResponse>(&result) {
                             Ok(mut data) => {
-                                for mut peer in &mut data.peers {
+                                for peer in &mut data.peers {
                                     peer.wg_interface = Some(wg.name.clone());
                                 }
                                 return Ok(data);
                             }
-                            Err(error) => return Err(format!("{}: {}", error.to_string(), &result)),
+                            Err(error) => return Err(format!("{}: {}", error, &result)),
                         }
                     }
                     Err(error) => return Err(error.to_string()),
                 }
             }
             Err(error) => {
-                return Err(error.to_string());
+                return Err(error);
             }
         }
     }
 
     Err("Could not serialize data".into())
 }
+
+/// Tell the server which proposed peers could be reached directly
+pub async fn peering_report(context: &QueryContext, wg: &NetworkInterface, connection: &Connection, reports: &[PeeringReport]) -> Result<(), String> {
+    let data = serde_json::to_string(reports).map_err(|error| error.to_string())?;
+
+    match post(context, wg, connection, "/peering-report", data, false).await?.error_for_status() {
+        Ok(_) => Ok(()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use crate::http::client::Connection;
+    use crate::http::discovery::{EndpointSource, ServerEndpoint};
+    use crate::http::peering::check_signature;
+    use crate::state::structs::{HttpSettings, ServerSettings};
+    use crate::state::testing::state;
+
+    fn connection(address: &str, source: EndpointSource) -> Connection {
+        let endpoint = ServerEndpoint { url: format!("http://{}", address), address: Some(address.parse().unwrap()), source };
+        Connection::new(ServerSettings::default(), endpoint, &HttpSettings::default()).unwrap()
+    }
+
+    #[test]
+    fn signature_required_once_signed() {
+        let state = state("signature-test.json");
+        let mut context = state.context();
+        let wg = &state.interfaces[0];
+        let first = connection("10.0.0.1", EndpointSource::FirstIp);
+        assert!(check_signature(&context, wg, &first, false).is_ok());
+        assert!(check_signature(&context, wg, &connection("10.0.0.1", EndpointSource::Txt), false).is_err());
+
+        check_signature(&context, wg, &first, true).unwrap();
+        assert!(check_signature(&context, wg, &first, false).is_err());
+        // a changed DNS answer moves the server, it still gets no unsigned requests
+        let moved = connection("192.168.1.5", EndpointSource::Config);
+        assert!(check_signature(&context, wg, &moved, false).is_err());
+        assert!(state.signed.lock().unwrap().contains("wg0"));
+
+        context.settings.servers.insert("wg0".into(), ServerSettings { require_signature: Some(false), ..Default::default() });
+        assert!(check_signature(&context, wg, &moved, false).is_ok());
+    }
+}
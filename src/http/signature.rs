@@ -0,0 +1,80 @@
+use base64::{Engine as _, engine::general_purpose};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::state::health::unix_time;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Context string that separates signing keys from other uses of the shared secret
+const KEY_CONTEXT: &[u8] = b"wireguard-web-autopeer peering v1";
+
+pub const TIMESTAMP_HEADER: &str = "X-Autopeer-Timestamp";
+pub const NONCE_HEADER: &str = "X-Autopeer-Nonce";
+pub const SIGNATURE_HEADER: &str = "X-Autopeer-Signature";
+
+
+/// Derive the request signing key from the X25519 shared secret of the interface key and the
+/// server key. The server derives the same key from its private key and our public key, so a
+/// valid signature proves we own the private key of the interface.
+pub fn signing_key(private_key: [u8; 32], server_pubkey: [u8; 32]) -> [u8; 32] {
+    let shared = StaticSecret::from(private_key).diffie_hellman(&PublicKey::from(server_pubkey));
+
+    let mut mac = HmacSha256::new_from_slice(shared.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(KEY_CONTEXT);
+    mac.finalize().into_bytes().into()
+}
+
+/// Base64 encoded HMAC-SHA256 over timestamp, nonce, path and body of a request
+pub fn sign(key: &[u8; 32], timestamp: u64, nonce: &str, path: &str, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(format!("{}\n{}\n{}\n", timestamp, nonce, path).as_bytes());
+    mac.update(body);
+    general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
+
+/// Headers that authenticate a request body sent to `path`
+pub fn signature_headers(key: &[u8; 32], path: &str, body: &[u8]) -> Vec<(&'static str, String)> {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let nonce = general_purpose::STANDARD.encode(nonce);
+    let timestamp = unix_time();
+
+    vec![
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (SIGNATURE_HEADER, sign(key, timestamp, &nonce, path, body)),
+        (NONCE_HEADER, nonce),
+    ]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use crate::http::signature::{sign, signing_key};
+
+    #[test]
+    fn both_sides_derive_the_same_signature() {
+        let client = [1u8; 32];
+        let server = [2u8; 32];
+        let client_pubkey = PublicKey::from(&StaticSecret::from(client)).to_bytes();
+        let server_pubkey = PublicKey::from(&StaticSecret::from(server)).to_bytes();
+
+        let client_key = signing_key(client, server_pubkey);
+        let server_key = signing_key(server, client_pubkey);
+        assert_eq!(client_key, server_key);
+
+        let body = br#"[{"pubkey": "abc"}]"#;
+        assert_eq!(sign(&client_key, 1000, "nonce", "/peering-request", body), sign(&server_key, 1000, "nonce", "/peering-request", body));
+        assert_ne!(sign(&client_key, 1000, "nonce", "/peering-request", body), sign(&client_key, 1001, "nonce", "/peering-request", body));
+    }
+
+    #[test]
+    fn other_key_gives_other_signature() {
+        let server_pubkey = PublicKey::from(&StaticSecret::from([2u8; 32])).to_bytes();
+        assert_ne!(signing_key([1u8; 32], server_pubkey), signing_key([3u8; 32], server_pubkey));
+    }
+}
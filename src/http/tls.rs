@@ -0,0 +1,237 @@
+use std::error::Error as StdError;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+
+use crate::state::structs::{parse_fingerprint, HttpSettings, ServerSettings};
+
+use super::discovery::ServerEndpoint;
+
+
+/// Colon separated hex notation of a fingerprint, the same format `openssl x509 -fingerprint` prints
+pub fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<String>>().join(":")
+}
+
+/// SHA-256 fingerprint of a DER encoded certificate
+pub fn certificate_fingerprint(certificate: &[u8]) -> [u8; 32] {
+    Sha256::digest(certificate).into()
+}
+
+/// Certificate verifier that checks the server certificate against a pinned fingerprint and/or a CA bundle
+struct PinningVerifier {
+    fingerprint: Option<[u8; 32]>,
+    webpki: Option<WebPkiVerifier>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(pinned) = &self.fingerprint {
+            let actual = certificate_fingerprint(&end_entity.0);
+            if &actual != pinned {
+                return Err(rustls::Error::General(format!(
+                    "server certificate fingerprint {} does not match pinned fingerprint {}",
+                    format_fingerprint(&actual),
+                    format_fingerprint(pinned)
+                )));
+            }
+        }
+
+        match &self.webpki {
+            Some(webpki) => webpki.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
+
+/// Load the CA certificates of a PEM bundle
+fn load_ca_bundle(path: &Path) -> Result<RootCertStore, String> {
+    let file = File::open(path).map_err(|error| format!("Could not open CA bundle {}: {}", path.display(), error))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|error| format!("Could not read CA bundle {}: {}", path.display(), error))?;
+
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certificates);
+    if added == 0 {
+        return Err(format!("CA bundle {} contains no usable certificate", path.display()));
+    }
+    Ok(roots)
+}
+
+/// HTTP client for the server of a wireguard interface. Without CA bundle and pinned certificate
+/// the built in root certificates are used, a pinned certificate alone skips the CA check.
+pub fn client(server: &ServerSettings, endpoint: &ServerEndpoint, http: &HttpSettings) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(http.connect_timeout))
+        .timeout(Duration::from_secs(http.request_timeout));
+
+    // use the address resolved over the tunnel instead of asking the system resolver again
+    if let (Ok(url), Some(address)) = (reqwest::Url::parse(&endpoint.url), endpoint.address) {
+        if let (Some(host), Some(port)) = (url.domain(), url.port_or_known_default()) {
+            builder = builder.resolve(host, SocketAddr::new(address, port));
+        }
+    }
+
+    if server.ca_bundle.is_none() && server.pinned_certificate.is_none() {
+        return builder.build().map_err(|error| error.to_string());
+    }
+
+    let fingerprint = match &server.pinned_certificate {
+        Some(fingerprint) => Some(parse_fingerprint(fingerprint)?),
+        None => None,
+    };
+    let webpki = match &server.ca_bundle {
+        Some(path) => Some(WebPkiVerifier::new(load_ca_bundle(path)?, None)),
+        None => None,
+    };
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinningVerifier { fingerprint, webpki }))
+        .with_no_client_auth();
+
+    builder.use_preconfigured_tls(config).build().map_err(|error| error.to_string())
+}
+
+/// Message of an error including all its causes, reqwest hides TLS errors in the source chain
+pub fn error_chain(error: &dyn StdError) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message = format!("{}: {}", message, cause_message);
+        }
+        source = cause.source();
+    }
+    message
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use rustls::client::{ServerCertVerifier, WebPkiVerifier};
+    use rustls::{Certificate, ServerName};
+
+    use crate::http::tls::{certificate_fingerprint, format_fingerprint, load_ca_bundle, PinningVerifier};
+    use crate::state::structs::parse_fingerprint;
+
+    /// Self-signed CA of `LEAF`
+    const CA: &str = "-----BEGIN CERTIFICATE-----\n\
+MIIBizCCATGgAwIBAgIUXk6+n6wM2o6TbhPUNJI/lAh55pAwCgYIKoZIzj0EAwIw\n\
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTgwNzAxMjlaGA8yMTI2MDkyNDA3\n\
+MDEyOVowEjEQMA4GA1UEAwwHVGVzdCBDQTBZMBMGByqGSM49AgEGCCqGSM49AwEH\n\
+A0IABOUbSzY3LwuzKqdtItBHZAyRnxCg8zfg7SX19GHLiYA7DBZoeHva5KqIk9iS\n\
+IM9ZXeCEo5O0mbvpkuNprBerlzejYzBhMB0GA1UdDgQWBBQH8xU2eedk9THk9JKz\n\
+PP8fTpesljAfBgNVHSMEGDAWgBQH8xU2eedk9THk9JKzPP8fTpesljAPBgNVHRMB\n\
+Af8EBTADAQH/MA4GA1UdDwEB/wQEAwICBDAKBggqhkjOPQQDAgNIADBFAiAinOfi\n\
+ky72k7OjvwVAKUn1SNDR5p4hFWUMJj67+GO9RwIhAMmfwUNrjVeDNoZNIZuwgDsH\n\
+14IMLDB6gK92AZOjDO03\n\
+-----END CERTIFICATE-----";
+    /// Certificate for localhost signed by `CA`
+    const LEAF: &str = "-----BEGIN CERTIFICATE-----\n\
+MIIBtzCCAV2gAwIBAgIUYBk2LVuj+XF5mE7vjXaAFV/L9PIwCgYIKoZIzj0EAwIw\n\
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTgwNzAxMjlaGA8yMTI2MDkyNDA3\n\
+MDEyOVowFDESMBAGA1UEAwwJbG9jYWxob3N0MFkwEwYHKoZIzj0CAQYIKoZIzj0D\n\
+AQcDQgAEF10KbMBC3L0x+N2xmh+zDqza28jHcfaNtLeJ4ZU2wwXSGXNoIfcvG0Uw\n\
+LkXOU3iGYmyOvdjbPdvhPWYBtGZR4qOBjDCBiTAUBgNVHREEDTALgglsb2NhbGhv\n\
+c3QwDAYDVR0TAQH/BAIwADATBgNVHSUEDDAKBggrBgEFBQcDATAOBgNVHQ8BAf8E\n\
+BAMCB4AwHQYDVR0OBBYEFNXpmztACIZVPI2P5G4T/PD6GqhPMB8GA1UdIwQYMBaA\n\
+FAfzFTZ552T1MeT0krM8/x9Ol6yWMAoGCCqGSM49BAMCA0gAMEUCIADwZAQ9/mzO\n\
+I3K2Pa7P7oO0aFtlVASgJXD5/J8cEgE0AiEApy8ux4oSVPn2J3C8rH9Cvz6i+p0W\n\
+d3mGba0FN4/LXcs=\n\
+-----END CERTIFICATE-----";
+    /// Self-signed CA that did not sign `LEAF`
+    const OTHER_CA: &str = "-----BEGIN CERTIFICATE-----\n\
+MIIBjDCCATOgAwIBAgIUcPD0DqFl1gs4oZerOi8Un5Xx9q8wCgYIKoZIzj0EAwIw\n\
+EzERMA8GA1UEAwwIT3RoZXIgQ0EwIBcNMjYxMDE4MDcwMTI5WhgPMjEyNjA5MjQw\n\
+NzAxMjlaMBMxETAPBgNVBAMMCE90aGVyIENBMFkwEwYHKoZIzj0CAQYIKoZIzj0D\n\
+AQcDQgAELQkgvmogbhoMTj7OR3W8g9WgkA1+IyUbVoHFI5bt1YUY5aCU2yAr3fRT\n\
+aduk85oiykf7MtiNEvi9W6LD3uHGzaNjMGEwHQYDVR0OBBYEFHePIITaPa0D0dVj\n\
+UmWhOYZCSwqrMB8GA1UdIwQYMBaAFHePIITaPa0D0dVjUmWhOYZCSwqrMA8GA1Ud\n\
+EwEB/wQFMAMBAf8wDgYDVR0PAQH/BAQDAgIEMAoGCCqGSM49BAMCA0cAMEQCIFrC\n\
+bVfpp8r/tt8v8cR5UREE78t0Zv+10FoeTT1ph6PZAiBjCjNxNNEtOrSFlbgTm9Yi\n\
+dSS+Hq0BTDaWsCx7UGtXSQ==\n\
+-----END CERTIFICATE-----";
+
+    fn der(pem: &str) -> Certificate {
+        Certificate(rustls_pemfile::certs(&mut pem.as_bytes()).unwrap().remove(0))
+    }
+
+    /// Verifier for `fingerprint` and the CA bundle `ca`, written to `file` in the temp dir
+    fn verifier(fingerprint: Option<[u8; 32]>, ca: Option<(&str, &str)>) -> PinningVerifier {
+        let webpki = ca.map(|(file, pem)| {
+            let path = std::env::temp_dir().join(file);
+            std::fs::write(&path, pem).unwrap();
+            let roots = load_ca_bundle(&path).unwrap();
+            std::fs::remove_file(path).unwrap();
+            WebPkiVerifier::new(roots, None)
+        });
+        PinningVerifier { fingerprint, webpki }
+    }
+
+    fn verify(verifier: &PinningVerifier) -> Result<(), String> {
+        let name = ServerName::try_from("localhost").unwrap();
+        verifier
+            .verify_server_cert(&der(LEAF), &[], &name, &mut std::iter::empty(), &[], SystemTime::now())
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn fingerprint_roundtrip() {
+        let fingerprint = certificate_fingerprint(b"certificate");
+        let formatted = format_fingerprint(&fingerprint);
+        assert_eq!(formatted.len(), 32 * 3 - 1);
+        assert_eq!(parse_fingerprint(&formatted), Ok(fingerprint));
+        assert_eq!(parse_fingerprint(&formatted.replace(':', "").to_lowercase()), Ok(fingerprint));
+    }
+
+    #[test]
+    fn invalid_fingerprint() {
+        assert!(parse_fingerprint("AB:CD").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+        assert!(parse_fingerprint(&"ä".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn pinned_certificate() {
+        let pinned = certificate_fingerprint(&der(LEAF).0);
+        let other = certificate_fingerprint(&der(OTHER_CA).0);
+
+        // a fingerprint alone skips the CA check, nothing trusts the CA of the certificate
+        assert_eq!(verify(&verifier(Some(pinned), None)), Ok(()));
+        let error = verify(&verifier(Some(other), None)).unwrap_err();
+        assert!(error.contains(&format!("server certificate fingerprint {} does not match pinned fingerprint {}", format_fingerprint(&pinned), format_fingerprint(&other))), "{}", error);
+    }
+
+    #[test]
+    fn pinned_certificate_and_ca() {
+        let pinned = certificate_fingerprint(&der(LEAF).0);
+        assert_eq!(verify(&verifier(Some(pinned), Some(("tls-test-ca.pem", CA)))), Ok(()));
+        assert_eq!(verify(&verifier(None, Some(("tls-test-ca-only.pem", CA)))), Ok(()));
+
+        // both have to match
+        let error = verify(&verifier(Some(pinned), Some(("tls-test-other-ca.pem", OTHER_CA)))).unwrap_err();
+        assert!(error.contains("UnknownIssuer"), "{}", error);
+        let error = verify(&verifier(Some(certificate_fingerprint(&der(CA).0)), Some(("tls-test-ca-mismatch.pem", CA)))).unwrap_err();
+        assert!(error.contains("does not match pinned fingerprint"), "{}", error);
+    }
+}
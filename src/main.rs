@@ -1,17 +1,21 @@
 #[macro_use]
-
 extern crate log;
 
 // Crate modules
 mod state;
 mod tray;
 mod autorefresh;
+mod healthcheck;
 mod network;
 mod wireguard;
 mod http;
+mod config;
+mod cli;
+#[cfg(unix)]
+mod control;
 
 // Everything tokio
-use tokio::{select, sync::mpsc::channel, signal::ctrl_c};
+use tokio::{select, sync::{mpsc::{channel, Sender}, watch}, signal::ctrl_c, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
 
 #[cfg(unix)]
@@ -25,24 +29,82 @@ use tray::Tray;
 
 // State keeping
 use state::messages::Message;
+use state::status::Status;
 use state::structs::StateManager;
 
+// Configuration
+use config::load_settings;
+use state::structs::Settings;
+use std::path::PathBuf;
+
+// Command line
+use clap::Parser;
+use cli::{Cli, Command, EXIT_OK, EXIT_CONFIG_ERROR};
+
 // Services
 use autorefresh::autorefresh;
+use healthcheck::healthcheck;
 use network::monitor::monitor;
+use network::utils::enumerate_networks;
+#[cfg(unix)]
+use control::server::control_socket;
 
 
-// Main loop
 #[tokio::main]
 async fn main() {
     // logging
     env_logger::init();
-        
+
+    // command line
+    let cli = Cli::parse();
+
+    // settings
+    let settings = match load_settings(cli.config.as_deref()) {
+        Ok(settings) => settings,
+        Err(error) => {
+            error!("Could not load settings: {}", error);
+            std::process::exit(EXIT_CONFIG_ERROR);
+        }
+    };
+
+    let code = match cli.command.unwrap_or(Command::Run) {
+        Command::Run => {
+            run(settings, cli.config, cli.dry_run).await;
+            EXIT_OK
+        }
+        Command::Sync { once } => cli::sync(settings, cli.dry_run, once).await,
+        Command::Status => cli::status(settings).await,
+        #[cfg(unix)]
+        Command::Ctl { action } => cli::control(settings, action).await,
+    };
+    std::process::exit(code);
+}
+
+
+// Main loop
+async fn run(settings: Settings, config_path: Option<PathBuf>, dry_run: bool) {
     // local state
-    let mut state = StateManager::new();
+    let mut state = StateManager::new(settings);
+    state.dry_run = dry_run;
     let (eventbus_tx, mut eventbus_rx) = channel::<Message>(32);
     info!("Running with settings: {}", serde_json::to_string(&state.settings).unwrap());
 
+    // status snapshot for the control socket
+    let (status_tx, status_rx) = watch::channel(Status::from(&state));
+
+    // Control socket, keeps running while suspended
+    let control_task = CancellationToken::new();
+    #[cfg(unix)]
+    let control_handle = match control_socket(state.settings.control_socket_path(), state.settings.control_socket_mode.into(), eventbus_tx.clone(), status_rx, control_task.clone()) {
+        Ok(handle) => Some(handle),
+        Err(error) => {
+            error!("Could not create control socket: {}", error);
+            None
+        }
+    };
+    #[cfg(not(unix))]
+    drop(status_rx);
+
     // Systray
     let (tray, tray_tx) = Tray::try_new(eventbus_tx.clone());
     if let Some(mut tray) = tray {
@@ -66,9 +128,19 @@ async fn main() {
     // cancellation tokens to suspend background tasks
     let mut background_tasks = CancellationToken::new();
 
-    // start auto refresh loop
-    let mut refresh_handle = Some(autorefresh(eventbus_tx.clone(), background_tasks.clone(), state.settings.refresh_timeout));    
-    let mut monitor_handle = Some(monitor(eventbus_tx.clone(), background_tasks.clone()));
+    // start auto refresh loop, it gets its own token to be able to restart it on settings changes
+    let mut refresh_task = background_tasks.child_token();
+    let mut refresh_handle = Some(autorefresh(eventbus_tx.clone(), refresh_task.clone(), state.settings.refresh_timeout));
+    let mut health_task = background_tasks.child_token();
+    let mut health_handle = Some(healthcheck(eventbus_tx.clone(), health_task.clone(), state.settings.health_check_interval));
+
+    // load the current networks before watching for changes, then query all interfaces
+    let networks = enumerate_networks();
+    state.load_networks(networks.clone()).await;
+    let mut monitor_handle = Some(monitor(eventbus_tx.clone(), background_tasks.clone(), state.settings.debounce, networks));
+
+    // query round running in the background
+    let mut queries: Option<JoinHandle<()>> = state.spawn_queries(eventbus_tx.clone());
 
     debug!("Entering main event loop...");
     'main: loop {
@@ -89,10 +161,17 @@ async fn main() {
                             let _ = &handle.await.unwrap();
                             refresh_handle = None;
                         }
+                        if let Some(handle) = health_handle {
+                            let _ = &handle.await.unwrap();
+                            health_handle = None;
+                        }
                         if let Some(handle) = monitor_handle {
                             let _ = &handle.await.unwrap();
                             monitor_handle = None;
                         }
+                        if let Some(handle) = queries.take() {
+                            handle.abort();
+                        }
                         state.suspended = true;
                     }
                     Message::Resume => {
@@ -104,25 +183,90 @@ async fn main() {
                         // network_monitor(eventbus_tx.clone(), backgroundTasks.clone());
                         refresh_handle = match refresh_handle {
                             Some(handle) => Some(handle),
-                            None => Some(autorefresh(eventbus_tx.clone(), background_tasks.clone(), state.settings.refresh_timeout))
+                            None => {
+                                refresh_task = background_tasks.child_token();
+                                Some(autorefresh(eventbus_tx.clone(), refresh_task.clone(), state.settings.refresh_timeout))
+                            }
+                        };
+                        health_handle = match health_handle {
+                            Some(handle) => Some(handle),
+                            None => {
+                                health_task = background_tasks.child_token();
+                                Some(healthcheck(eventbus_tx.clone(), health_task.clone(), state.settings.health_check_interval))
+                            }
                         };
                         monitor_handle = match monitor_handle {
                             Some(handle) => Some(handle),
-                            None => Some(monitor(eventbus_tx.clone(), background_tasks.clone()))
+                            None => {
+                                // networks may have changed while suspended
+                                let networks = enumerate_networks();
+                                state.load_networks(networks.clone()).await;
+                                queries = restart_queries(&mut state, queries, &eventbus_tx);
+                                Some(monitor(eventbus_tx.clone(), background_tasks.clone(), state.settings.debounce, networks))
+                            }
                         };
                     }
-                    Message::InterfacesLoaded => {
-                        state.suspended = false;
-                        state.refresh().await;
+                    Message::NetworkChanged(change) => {
+                        info!("Network changed: {}", change);
+                        let mut changed = !change.removed.is_empty();
+                        for net in change.removed {
+                            state.ifdown(net).await;
+                        }
+                        for net in change.added {
+                            changed |= state.ifup(net).await;
+                        }
+                        // one query round for the whole change, a running round is superseded
+                        if changed {
+                            queries = restart_queries(&mut state, queries, &eventbus_tx);
+                        }
+                    }
+                    Message::RoutesChanged => {
+                        if state.update_routes().await {
+                            queries = restart_queries(&mut state, queries, &eventbus_tx);
+                        }
+                    }
+                    Message::RefreshPeers => {
+                        // nothing changed since a running round started, let it finish
+                        if queries.as_ref().is_some_and(|handle| !handle.is_finished()) {
+                            debug!("Query round still running, skipping refresh");
+                        } else {
+                            queries = restart_queries(&mut state, queries, &eventbus_tx);
+                        }
                     }
-                    Message::InterfaceUp(interface) => state.ifup(interface).await,
-                    Message::InterfaceDown(interface) => state.ifdown(interface).await,
-                    Message::RefreshPeers => state.refresh().await,
+                    Message::QueriesFinished(results) => {
+                        state.apply_queries(*results).await;
+                    }
+                    Message::CheckHealth => state.spawn_health_check().await,
                 }
             }
             _ = hup.recv() => {
-                info!("Received HUP, Reloading all peers...");
-                state.refresh().await;
+                info!("Received HUP, Reloading settings and all peers...");
+                match load_settings(config_path.as_deref()) {
+                    Ok(settings) => {
+                        info!("Running with settings: {}", serde_json::to_string(&settings).unwrap());
+                        // restart auto refresh loop with new timeout
+                        if settings.refresh_timeout != state.settings.refresh_timeout {
+                            if let Some(handle) = refresh_handle {
+                                refresh_task.cancel();
+                                let _ = &handle.await.unwrap();
+                                refresh_task = background_tasks.child_token();
+                                refresh_handle = Some(autorefresh(eventbus_tx.clone(), refresh_task.clone(), settings.refresh_timeout));
+                            }
+                        }
+                        // restart health check loop with new interval
+                        if settings.health_check_interval != state.settings.health_check_interval {
+                            if let Some(handle) = health_handle {
+                                health_task.cancel();
+                                let _ = &handle.await.unwrap();
+                                health_task = background_tasks.child_token();
+                                health_handle = Some(healthcheck(eventbus_tx.clone(), health_task.clone(), settings.health_check_interval));
+                            }
+                        }
+                        state.settings = settings;
+                    }
+                    Err(error) => error!("Could not reload settings, keeping old ones: {}", error),
+                }
+                queries = restart_queries(&mut state, queries, &eventbus_tx);
             }
             _ = term.recv() => {
                 info!("Received TERM, Shutting down...");
@@ -133,6 +277,7 @@ async fn main() {
                 break 'main;
             }
         }
+        status_tx.send_replace(Status::from(&state));
     }
     
     // Shutdown all services
@@ -140,11 +285,33 @@ async fn main() {
         tx.send(Message::Quit).await.expect("Failed to send quit messaage to systray");
     }
     background_tasks.cancel();
+    if let Some(handle) = queries {
+        handle.abort();
+    }
     if let Some(handle) = refresh_handle {
         let _ = &handle.await.unwrap();
     }
+    if let Some(handle) = health_handle {
+        let _ = &handle.await.unwrap();
+    }
     if let Some(handle) = monitor_handle {
         let _ = &handle.await.unwrap();
     }
+    state.shutdown().await;
+    control_task.cancel();
+    #[cfg(unix)]
+    if let Some(handle) = control_handle {
+        let _ = &handle.await.unwrap();
+    }
+}
 
+/// Start a new query round, a round that is still running is superseded and aborted
+fn restart_queries(state: &mut StateManager, running: Option<JoinHandle<()>>, tx: &Sender<Message>) -> Option<JoinHandle<()>> {
+    if let Some(handle) = running {
+        if !handle.is_finished() {
+            debug!("Aborting superseded query round");
+        }
+        handle.abort();
+    }
+    state.spawn_queries(tx.clone())
 }
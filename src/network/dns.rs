@@ -0,0 +1,279 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+
+const CLASS_IN: u16 = 1;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+
+/// Answer record of a DNS response, unknown record types are skipped
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Address(IpAddr),
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+    Txt(String),
+}
+
+/// Name server and search domain of the system resolver
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResolverConfig {
+    pub nameserver: Option<IpAddr>,
+    pub domain: Option<String>,
+}
+
+impl ResolverConfig {
+    /// Read the first name server and the first search domain from `/etc/resolv.conf`
+    pub fn system() -> Self {
+        fs::read_to_string(RESOLV_CONF).map(|content| Self::parse(&content)).unwrap_or_default()
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let mut result = Self::default();
+        for line in content.lines() {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(address)) if result.nameserver.is_none() => {
+                    // strip the zone of link local addresses, e.g. fe80::1%eth0
+                    result.nameserver = address.split('%').next().and_then(|address| address.parse().ok());
+                }
+                (Some("domain" | "search"), Some(domain)) if result.domain.is_none() => {
+                    result.domain = Some(domain.trim_end_matches('.').to_string());
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+}
+
+
+/// Encode a DNS query for one name and record type
+pub fn build_query(id: u16, name: &str, record_type: u16) -> Result<Vec<u8>, String> {
+    // header: id, recursion desired, one question
+    let mut data = vec![];
+    data.extend_from_slice(&id.to_be_bytes());
+    data.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || (label.len() > 63) {
+            return Err(format!("{} is not a valid DNS name", name));
+        }
+        data.push(label.len() as u8);
+        data.extend_from_slice(label.as_bytes());
+    }
+    data.push(0);
+    data.extend_from_slice(&record_type.to_be_bytes());
+    data.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(data)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, String> {
+    match data.get(pos..pos + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err("Truncated DNS response".into()),
+    }
+}
+
+/// Read a possibly compressed name, returns the name and the position after it
+fn read_name(data: &[u8], mut pos: usize) -> Result<(String, usize), String> {
+    let mut labels: Vec<String> = vec![];
+    let mut end = None;
+
+    // every jump has to go backwards, this also limits the number of jumps
+    let mut limit = pos;
+    loop {
+        let length = *data.get(pos).ok_or("Truncated DNS response")? as usize;
+        if length & 0xC0 == 0xC0 {
+            let target = (read_u16(data, pos)? & 0x3FFF) as usize;
+            if target >= limit {
+                return Err("Invalid compression pointer in DNS response".into());
+            }
+            end.get_or_insert(pos + 2);
+            limit = target;
+            pos = target;
+        } else if length == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        } else {
+            let label = data.get(pos + 1..pos + 1 + length).ok_or("Truncated DNS response")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + length;
+        }
+    }
+}
+
+/// Decode the answers of a DNS response to the query with `id`
+pub fn parse_response(id: u16, data: &[u8]) -> Result<Vec<Record>, String> {
+    if read_u16(data, 0)? != id {
+        return Err("DNS response does not match the query".into());
+    }
+    match read_u16(data, 2)? & 0x000F {
+        0 => {}
+        // name does not exist
+        3 => return Ok(vec![]),
+        code => return Err(format!("DNS server returned error code {}", code)),
+    }
+
+    let questions = read_u16(data, 4)?;
+    let answers = read_u16(data, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(data, pos)?.1 + 4;
+    }
+
+    let mut result = vec![];
+    for _ in 0..answers {
+        pos = read_name(data, pos)?.1;
+        let record_type = read_u16(data, pos)?;
+        let length = read_u16(data, pos + 8)? as usize;
+        let start = pos + 10;
+        let rdata = data.get(start..start + length).ok_or("Truncated DNS response")?;
+        pos = start + length;
+
+        match (record_type, length) {
+            (TYPE_A, 4) => result.push(Record::Address(IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])))),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                result.push(Record::Address(IpAddr::V6(Ipv6Addr::from(octets))));
+            }
+            (TYPE_SRV, _) => result.push(Record::Srv {
+                priority: read_u16(data, start)?,
+                weight: read_u16(data, start + 2)?,
+                port: read_u16(data, start + 4)?,
+                target: read_name(data, start + 6)?.0,
+            }),
+            (TYPE_TXT, _) => {
+                // a TXT record consists of length prefixed strings that belong together
+                let mut text = String::new();
+                let mut index = 0;
+                while index < rdata.len() {
+                    let part = rdata.get(index + 1..index + 1 + rdata[index] as usize).ok_or("Truncated DNS response")?;
+                    text.push_str(&String::from_utf8_lossy(part));
+                    index += 1 + part.len();
+                }
+                result.push(Record::Txt(text));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(result)
+}
+
+/// Query one record type of a name from a DNS server over UDP
+pub async fn query(server: IpAddr, name: &str, record_type: u16) -> Result<Vec<Record>, String> {
+    query_address(SocketAddr::new(server, 53), name, record_type).await
+}
+
+async fn query_address(server: SocketAddr, name: &str, record_type: u16) -> Result<Vec<Record>, String> {
+    let id: u16 = rand::random();
+    let request = build_query(id, name, record_type)?;
+
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await.map_err(|error| error.to_string())?;
+    socket.connect(server).await.map_err(|error| format!("{}: {}", server, error))?;
+    socket.send(&request).await.map_err(|error| format!("{}: {}", server, error))?;
+
+    let mut buffer = [0u8; 1500];
+    match timeout(QUERY_TIMEOUT, socket.recv(&mut buffer)).await {
+        Ok(Ok(length)) => parse_response(id, &buffer[..length]),
+        Ok(Err(error)) => Err(format!("{}: {}", server, error)),
+        Err(_) => Err(format!("DNS server {} did not answer", server)),
+    }
+}
+
+/// Resolve the addresses of a host name, IPv4 first
+pub async fn resolve_host(server: IpAddr, name: &str) -> Result<Vec<IpAddr>, String> {
+    let mut result = vec![];
+    for record_type in [TYPE_A, TYPE_AAAA] {
+        for record in query(server, name, record_type).await? {
+            if let Record::Address(address) = record {
+                result.push(address);
+            }
+        }
+    }
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+
+    use crate::network::dns::{build_query, parse_response, query_address, Record, ResolverConfig, TYPE_SRV, TYPE_TXT};
+
+    /// Response to `build_query(id, "_wireguard-web._tcp.example.org", TYPE_SRV)` with a compressed target
+    fn srv_response(id: u16) -> Vec<u8> {
+        let mut data = build_query(id, "_wireguard-web._tcp.example.org", TYPE_SRV).unwrap();
+        data[2] = 0x81;
+        data[3] = 0x80;
+        data[7] = 1;
+        // answer: pointer to the question name, SRV, IN, ttl 60
+        data.extend_from_slice(&[0xC0, 12, 0, 33, 0, 1, 0, 0, 0, 60]);
+        // rdata: priority 10, weight 5, port 8443, "vpn" + pointer to "example.org"
+        data.extend_from_slice(&[0, 12, 0, 10, 0, 5, 0x20, 0xFB, 3, b'v', b'p', b'n', 0xC0, 32]);
+        data
+    }
+
+    #[test]
+    fn parse_srv() {
+        let records = parse_response(7, &srv_response(7)).unwrap();
+        assert_eq!(records, vec![Record::Srv { priority: 10, weight: 5, port: 8443, target: "vpn.example.org".into() }]);
+        assert!(parse_response(8, &srv_response(7)).is_err());
+    }
+
+    #[test]
+    fn parse_txt() {
+        let mut data = build_query(1, "example.org", TYPE_TXT).unwrap();
+        data[7] = 1;
+        data.extend_from_slice(&[0xC0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0, 8, 3, b'u', b'r', b'l', 3, b'=', b'/', b'a']);
+        assert_eq!(parse_response(1, &data).unwrap(), vec![Record::Txt("url=/a".into())]);
+    }
+
+    #[test]
+    fn pointer_loop_is_rejected() {
+        let mut data = build_query(1, "example.org", TYPE_TXT).unwrap();
+        data[7] = 1;
+        let pos = data.len() as u8;
+        data.extend_from_slice(&[0xC0, pos]);
+        assert!(parse_response(1, &data).is_err());
+    }
+
+    #[test]
+    fn resolv_conf() {
+        let config = ResolverConfig::parse("# comment\nsearch lan.example.org. other.org\nnameserver fe80::1%eth0\nnameserver 10.0.0.1\n");
+        assert_eq!(config.nameserver, Some("fe80::1".parse().unwrap()));
+        assert_eq!(config.domain, Some("lan.example.org".into()));
+    }
+
+    #[tokio::test]
+    async fn query_local_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address: SocketAddr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            let (_, client) = server.recv_from(&mut buffer).await.unwrap();
+            let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+            server.send_to(&srv_response(id), client).await.unwrap();
+        });
+
+        let records = query_address(address, "_wireguard-web._tcp.example.org", TYPE_SRV).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(matches!(&records[0], Record::Srv { port: 8443, .. }));
+    }
+}
@@ -1,2 +1,6 @@
+pub mod dns;
 pub mod monitor;
+pub mod neighbour;
+pub mod routes;
+pub mod stun;
 pub mod utils;
\ No newline at end of file
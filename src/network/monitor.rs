@@ -1,42 +1,248 @@
-use futures::StreamExt;
-use if_watch::{tokio::IfWatcher, IfEvent};
-use tokio::{sync::mpsc::Sender, task::JoinHandle, select};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::Duration;
+
+use futures::{stream, StreamExt};
+use if_watch::{tokio::IfWatcher, IfEvent, IpNet};
+use net_route::{Handle, RouteChange};
+use tokio::{sync::mpsc::Sender, task::JoinHandle, select, time::{sleep_until, Instant}};
 use tokio_util::sync::CancellationToken;
 
-use crate::state::{messages::Message};
+use crate::state::{messages::Message, structs::Debounce};
 
+/// A burst is cut off after this many debounce windows even if addresses keep changing
+const MAX_WINDOWS: u32 = 5;
+/// Routing table of the local addresses, they change with the addresses
+#[cfg(target_os = "linux")]
+const TABLE_LOCAL: u8 = 255;
 
-pub fn monitor(tx: Sender<Message>, cancel: CancellationToken) -> JoinHandle<()> {
-    let tx_clone = tx.clone();
-    tokio::spawn(async move {
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-        let _ = tx_clone.send(Message::InterfacesLoaded).await.unwrap();
-    });
+
+/// Networks that appeared and disappeared during a burst of address changes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkChange {
+    pub added: Vec<IpNet>,
+    pub removed: Vec<IpNet>,
+}
+
+impl NetworkChange {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl fmt::Display for NetworkChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let added = self.added.iter().map(|net| format!("+{}", net));
+        let removed = self.removed.iter().map(|net| format!("-{}", net));
+        write!(f, "{}", added.chain(removed).collect::<Vec<String>>().join(" "))
+    }
+}
+
+/// Collects address events and compares the networks before and after a burst, an address that
+/// went down and came back up again is no change
+#[derive(Debug, Default)]
+struct Coalescer {
+    /// Networks at the end of the last burst
+    before: BTreeSet<IpNet>,
+    /// Networks now
+    after: BTreeSet<IpNet>,
+}
+
+impl Coalescer {
+    /// Start with the `known` networks, the first burst has to report every network that still
+    /// exists, known networks it does not report are gone
+    fn new(known: Vec<IpNet>) -> Self {
+        Self { before: known.into_iter().collect(), after: BTreeSet::new() }
+    }
+
+    fn event(&mut self, event: IfEvent) {
+        match event {
+            IfEvent::Up(net) => self.after.insert(net),
+            IfEvent::Down(net) => self.after.remove(&net),
+        };
+    }
+
+    /// End the burst and return what changed during it
+    fn flush(&mut self) -> NetworkChange {
+        let change = NetworkChange {
+            added: self.after.difference(&self.before).copied().collect(),
+            removed: self.before.difference(&self.after).copied().collect(),
+        };
+        self.before = self.after.clone();
+        change
+    }
+}
+
+/// End of a burst, every event pushes it back by one window up to `MAX_WINDOWS` after the first event
+#[derive(Debug, Default)]
+struct Burst {
+    deadline: Option<(Instant, Instant)>,
+}
+
+impl Burst {
+    fn event(&mut self, window: Duration) {
+        let now = Instant::now();
+        let limit = self.deadline.map(|(_, limit)| limit).unwrap_or(now + window * MAX_WINDOWS);
+        self.deadline = Some(((now + window).min(limit), limit));
+    }
+
+    fn is_running(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    fn end(&self) -> Instant {
+        self.deadline.map(|(end, _)| end).unwrap_or_else(Instant::now)
+    }
+}
+
+/// Check if a route change may affect next hops, routes of the local addresses change with the
+/// addresses
+fn affects_next_hops(change: &RouteChange) -> bool {
+    let (RouteChange::Add(route) | RouteChange::Delete(route) | RouteChange::Change(route)) = change;
+    #[cfg(target_os = "linux")]
+    if route.table == TABLE_LOCAL {
+        return false;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = route;
+    true
+}
+
+
+/// Watch address changes, `known` are the networks that were loaded at startup. The watcher
+/// reports all existing addresses first, only addresses that differ from `known` are a change.
+/// Known networks the watcher does not report were removed before it started.
+pub fn monitor(tx: Sender<Message>, cancel: CancellationToken, debounce: Debounce, known: Vec<IpNet>) -> JoinHandle<()> {
     tokio::spawn(async move {
+        let window = Duration::from_millis(debounce.into());
         let mut watcher = IfWatcher::new().unwrap();
+        let mut coalescer = Coalescer::new(known);
+        // the existing addresses are the first burst, it also ends if there are none
+        let mut addresses = Burst::default();
+        addresses.event(window);
+
+        // gateway and metric changes do not touch the addresses
+        let routes = Handle::new();
+        let mut route_changes = match &routes {
+            Ok(handle) => handle.route_listen_stream().boxed(),
+            Err(error) => {
+                error!("Could not watch the routing table: {:?}", error);
+                stream::pending().boxed()
+            }
+        };
+        let mut route_burst = Burst::default();
+
         loop {
             select! {
                 // cancelled, break loop, exit task
                 _ = cancel.cancelled() => {
                     break;
                 }
+                // addresses settled, send one change for the whole burst
+                _ = sleep_until(addresses.end()), if addresses.is_running() => {
+                    addresses = Burst::default();
+                    let change = coalescer.flush();
+                    if !change.is_empty() {
+                        tx.send(Message::NetworkChanged(change)).await.unwrap();
+                    }
+                }
+                // routes settled
+                _ = sleep_until(route_burst.end()), if route_burst.is_running() => {
+                    route_burst = Burst::default();
+                    tx.send(Message::RoutesChanged).await.unwrap();
+                }
                 // New network interface event
                 event = watcher.select_next_some() => {
-                    match event {
-                        Ok(IfEvent::Up(ip)) => {
-                            if !ip.addr().is_loopback() {
-                                tx.send(Message::InterfaceUp(ip)).await.unwrap();
-                            }
-                        }
-                        Ok(IfEvent::Down(ip)) => {
-                            if !ip.addr().is_loopback() {
-                                tx.send(Message::InterfaceDown(ip)).await.unwrap();
-                            }
+                    if let Ok(event) = event {
+                        let net = match &event {
+                            IfEvent::Up(net) | IfEvent::Down(net) => net,
+                        };
+                        if net.addr().is_loopback() {
+                            continue;
                         }
-                        Err(_) => ()
+                        debug!("Address event: {:?}", event);
+                        coalescer.event(event);
+                        addresses.event(window);
+                    }
+                }
+                // New route event
+                Some(change) = route_changes.next() => {
+                    // the default flag compares all networks, any route can move it
+                    if affects_next_hops(&change) {
+                        debug!("Route event: {:?}", change);
+                        route_burst.event(window);
                     }
                 }
             }
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use if_watch::{IfEvent, IpNet};
+    use net_route::{Route, RouteChange};
+
+    use crate::network::monitor::{affects_next_hops, Coalescer};
+
+    fn net(net: &str) -> IpNet {
+        net.parse().unwrap()
+    }
+
+    #[test]
+    fn known_networks_are_no_change() {
+        let mut coalescer = Coalescer::new(vec![net("192.168.1.10/24")]);
+        coalescer.event(IfEvent::Up(net("192.168.1.10/24")));
+        assert!(coalescer.flush().is_empty());
+
+        // appeared between enumeration and the start of the watcher
+        coalescer.event(IfEvent::Up(net("10.0.0.5/24")));
+        assert_eq!(coalescer.flush().added, vec![net("10.0.0.5/24")]);
+    }
+
+    #[test]
+    fn unreported_networks_are_gone() {
+        // removed between enumeration and the start of the watcher
+        let mut coalescer = Coalescer::new(vec![net("192.168.1.10/24"), net("10.0.0.5/24")]);
+        coalescer.event(IfEvent::Up(net("192.168.1.10/24")));
+        let change = coalescer.flush();
+        assert!(change.added.is_empty());
+        assert_eq!(change.removed, vec![net("10.0.0.5/24")]);
+
+        let mut coalescer = Coalescer::new(vec![net("192.168.1.10/24")]);
+        assert_eq!(coalescer.flush().removed, vec![net("192.168.1.10/24")]);
+    }
+
+    #[test]
+    fn burst_is_coalesced() {
+        let mut coalescer = Coalescer::default();
+        coalescer.event(IfEvent::Up(net("192.168.1.10/24")));
+        coalescer.event(IfEvent::Up(net("fd00::10/64")));
+        let change = coalescer.flush();
+        assert_eq!(change.added, vec![net("192.168.1.10/24"), net("fd00::10/64")]);
+        assert!(change.removed.is_empty());
+
+        // roaming: the old address goes away, a new one comes and a renewal flaps the IPv6 address
+        coalescer.event(IfEvent::Down(net("192.168.1.10/24")));
+        coalescer.event(IfEvent::Down(net("fd00::10/64")));
+        coalescer.event(IfEvent::Up(net("10.0.0.5/24")));
+        coalescer.event(IfEvent::Up(net("fd00::10/64")));
+        let change = coalescer.flush();
+        assert_eq!(change.added, vec![net("10.0.0.5/24")]);
+        assert_eq!(change.removed, vec![net("192.168.1.10/24")]);
+        assert_eq!(change.to_string(), "+10.0.0.5/24 -192.168.1.10/24");
+
+        assert!(coalescer.flush().is_empty());
+    }
+
+    #[test]
+    fn route_changes() {
+        let gateway = Route::new("0.0.0.0".parse().unwrap(), 0).with_gateway("192.168.1.1".parse().unwrap()).with_ifindex(2);
+        assert!(affects_next_hops(&RouteChange::Change(gateway.clone())));
+        assert!(affects_next_hops(&RouteChange::Delete(gateway)));
+        assert!(affects_next_hops(&RouteChange::Add(Route::new("10.0.0.0".parse().unwrap(), 8))));
+
+        #[cfg(target_os = "linux")]
+        assert!(!affects_next_hops(&RouteChange::Add(Route::new("192.168.1.10".parse().unwrap(), 32).with_ifindex(2).with_table(255))));
+    }
+}
@@ -0,0 +1,89 @@
+use std::net::IpAddr;
+
+use sha2::{Digest, Sha256};
+
+/// Context string that separates network fingerprints from other hashes of the same address
+const FINGERPRINT_CONTEXT: &str = "wireguard-web-autopeer network v1";
+
+
+/// Colon separated hex notation of a MAC address
+pub fn format_mac(mac: &[u8]) -> String {
+    mac.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join(":")
+}
+
+/// Fingerprint of a layer 2 network from the MAC address of its gateway, the server compares them
+/// to tell apart networks with the same addresses. The hash is not keyed, the server can find the
+/// MAC by hashing candidate addresses.
+pub fn network_fingerprint(gateway_mac: &[u8]) -> String {
+    let mut hash = Sha256::new();
+    hash.update(FINGERPRINT_CONTEXT.as_bytes());
+    hash.update(b"\n");
+    hash.update(format_mac(gateway_mac).as_bytes());
+    hash.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// MAC address of a neighbour on an interface from the kernel neighbour table, entries that are
+/// not resolved are ignored
+#[cfg(target_os = "linux")]
+pub async fn neighbour_mac(ip: IpAddr, interface: u32) -> Option<Vec<u8>> {
+    use futures::TryStreamExt;
+    use netlink_packet_route::neighbour::Nla;
+    use netlink_packet_route::{NUD_FAILED, NUD_INCOMPLETE, NUD_NOARP};
+    use rtnetlink::IpVersion;
+
+    let (connection, handle, _) = match rtnetlink::new_connection() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!("Could not read neighbour table: {}", error);
+            return None;
+        }
+    };
+    let connection = tokio::spawn(connection);
+
+    let version = if ip.is_ipv4() { IpVersion::V4 } else { IpVersion::V6 };
+    let destination = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    let mut result = None;
+    let mut neighbours = handle.neighbours().get().set_family(version).execute();
+    while let Ok(Some(neighbour)) = neighbours.try_next().await {
+        if (neighbour.header.ifindex != interface) || (neighbour.header.state & (NUD_FAILED | NUD_INCOMPLETE | NUD_NOARP) != 0) {
+            continue;
+        }
+        if !neighbour.nlas.iter().any(|nla| matches!(nla, Nla::Destination(address) if *address == destination)) {
+            continue;
+        }
+        result = neighbour.nlas.iter().find_map(|nla| match nla {
+            Nla::LinkLocalAddress(mac) => Some(mac.clone()),
+            _ => None,
+        });
+        if result.is_some() {
+            break;
+        }
+    }
+
+    connection.abort();
+    result
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn neighbour_mac(_ip: IpAddr, _interface: u32) -> Option<Vec<u8>> {
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::network::neighbour::{format_mac, network_fingerprint};
+
+    #[test]
+    fn fingerprint() {
+        let mac = [0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e];
+        assert_eq!(format_mac(&mac), "00:1a:2b:3c:4d:5e");
+        assert_eq!(network_fingerprint(&mac).len(), 64);
+        assert_eq!(network_fingerprint(&mac), network_fingerprint(&mac));
+        assert_ne!(network_fingerprint(&mac), network_fingerprint(&[0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5f]));
+    }
+}
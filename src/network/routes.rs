@@ -0,0 +1,398 @@
+use std::net::IpAddr;
+
+use if_watch::IpNet;
+
+/// Routing table of routes without a table, see `RT_TABLE_MAIN`
+pub const TABLE_MAIN: u32 = 254;
+/// Table of the last built in rule, see `RT_TABLE_DEFAULT`
+pub const TABLE_DEFAULT: u32 = 253;
+
+
+/// One route of a routing table
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteEntry {
+    pub destination: IpNet,
+    pub gateway: Option<IpAddr>,
+    pub ifindex: Option<u32>,
+    /// Lower is better, unknown if the platform does not report metrics
+    pub metric: Option<u32>,
+    pub table: u32,
+}
+
+/// Policy routing rule that looks up a table, see `ip rule`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutingRule {
+    pub priority: u32,
+    pub table: u32,
+    /// Mark and mask the packet mark has to match
+    pub fwmark: Option<(u32, u32)>,
+    /// Source network the packet has to come from
+    pub source: Option<IpNet>,
+    /// `not` rules match all packets the selectors do not match
+    pub invert: bool,
+    /// Routes with a prefix up to this length are ignored, wg-quick uses 0 to skip default routes of the main table
+    pub suppress_prefix_length: Option<u8>,
+}
+
+impl RoutingRule {
+    /// Rule that looks up a table for all packets
+    pub fn lookup(priority: u32, table: u32) -> Self {
+        Self { priority, table, fwmark: None, source: None, invert: false, suppress_prefix_length: None }
+    }
+
+    fn matches(&self, source: IpAddr, fwmark: u32) -> bool {
+        let marked = self.fwmark.is_none_or(|(mark, mask)| fwmark & mask == mark);
+        let from = self.source.is_none_or(|net| net.contains(&source));
+        (marked && from) != self.invert
+    }
+
+    fn allows(&self, route: &RouteEntry) -> bool {
+        self.suppress_prefix_length.is_none_or(|length| route.destination.prefix_len() > length)
+    }
+}
+
+/// Next hop of a network
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NextHop {
+    pub gateway: Option<IpAddr>,
+    /// The network holds the default route that wins for its address family
+    pub default: bool,
+    /// Metric of the default route or of the route to the network
+    pub metric: Option<u32>,
+}
+
+/// Routes of all tables and the policy rules selecting the tables
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoutingTable {
+    pub routes: Vec<RouteEntry>,
+    /// Without rules only the main table is used
+    pub rules: Vec<RoutingRule>,
+}
+
+impl RoutingTable {
+    /// Rules that apply to packets from `source` with `fwmark`, in the order the kernel tries them
+    fn rules(&self, source: IpAddr, fwmark: u32) -> Vec<RoutingRule> {
+        let mut rules: Vec<RoutingRule> = match self.rules.is_empty() {
+            true => vec![RoutingRule::lookup(32766, TABLE_MAIN), RoutingRule::lookup(32767, TABLE_DEFAULT)],
+            false => self.rules.iter().filter(|rule| rule.matches(source, fwmark)).cloned().collect(),
+        };
+        rules.sort_by_key(|rule| rule.priority);
+        rules
+    }
+
+    /// Best route of the first rule that has one, lowest metric first
+    fn lookup<F: Fn(&RouteEntry) -> bool>(&self, rules: &[RoutingRule], filter: F) -> Option<&RouteEntry> {
+        rules.iter().find_map(|rule| {
+            self.routes
+                .iter()
+                .filter(|route| (route.table == rule.table) && rule.allows(route) && filter(route))
+                .min_by_key(|route| route.metric.unwrap_or(u32::MAX))
+        })
+    }
+
+    /// Next hop of a network on the interface with `ifindex` for packets with `fwmark`, that is
+    /// a gateway to the network itself or the default gateway on the interface
+    pub fn next_hop(&self, net: IpNet, ifindex: u32, fwmark: u32) -> NextHop {
+        let rules = self.rules(net.addr(), fwmark);
+        let family = |route: &RouteEntry| route.destination.addr().is_ipv4() == net.addr().is_ipv4();
+        let on_interface = |route: &RouteEntry| route.ifindex == Some(ifindex);
+
+        // the network is behind a router
+        let network = self.lookup(&rules, |route| (route.destination == net.trunc()) && route.gateway.is_some());
+        if let Some(route) = network {
+            return NextHop { gateway: route.gateway, default: false, metric: route.metric };
+        }
+
+        let default = self.lookup(&rules, |route| family(route) && (route.destination.prefix_len() == 0) && on_interface(route) && route.gateway.is_some());
+        if let Some(route) = default {
+            // the default route that wins on any interface
+            let best = self.lookup(&rules, |route| family(route) && (route.destination.prefix_len() == 0));
+            return NextHop { gateway: route.gateway, default: best == Some(route), metric: route.metric };
+        }
+
+        let connected = self.lookup(&rules, |route| (route.destination == net.trunc()) && on_interface(route));
+        NextHop { gateway: None, default: false, metric: connected.and_then(|route| route.metric) }
+    }
+}
+
+
+/// Source of the routing table
+pub trait RouteResolver {
+    async fn routing_table(&self) -> Result<RoutingTable, String>;
+
+    /// Next hop of a network on an interface for packets with `fwmark`, see `RoutingTable::next_hop`
+    async fn next_hop(&self, net: IpNet, ifindex: u32, fwmark: u32) -> NextHop {
+        match self.routing_table().await {
+            Ok(table) => table.next_hop(net, ifindex, fwmark),
+            Err(error) => {
+                error!("Could not fetch routing table: {}", error);
+                NextHop::default()
+            }
+        }
+    }
+}
+
+impl RouteResolver for RoutingTable {
+    async fn routing_table(&self) -> Result<RoutingTable, String> {
+        Ok(self.clone())
+    }
+}
+
+/// Routes of all tables and the policy rules from the kernel
+#[cfg(target_os = "linux")]
+pub struct NetlinkResolver;
+
+#[cfg(target_os = "linux")]
+impl NetlinkResolver {
+    fn address(bytes: &[u8]) -> Option<IpAddr> {
+        match bytes.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+            _ => None,
+        }
+    }
+
+    fn unspecified(version: &rtnetlink::IpVersion) -> IpAddr {
+        match version {
+            rtnetlink::IpVersion::V4 => IpAddr::from([0u8; 4]),
+            rtnetlink::IpVersion::V6 => IpAddr::from([0u8; 16]),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl RouteResolver for NetlinkResolver {
+    async fn routing_table(&self) -> Result<RoutingTable, String> {
+        use futures::TryStreamExt;
+        use netlink_packet_route::{route, rule, FIB_RULE_INVERT, FR_ACT_TO_TBL, RTN_UNICAST};
+        use rtnetlink::IpVersion;
+
+        let (connection, handle, _) = rtnetlink::new_connection().map_err(|error| error.to_string())?;
+        let connection = tokio::spawn(connection);
+
+        let mut table = RoutingTable::default();
+        let mut result = Ok(());
+        for version in [IpVersion::V4, IpVersion::V6] {
+            let unspecified = Self::unspecified(&version);
+
+            let mut routes = handle.route().get(version.clone()).execute();
+            loop {
+                let message = match routes.try_next().await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break,
+                    Err(error) => {
+                        result = Err(error.to_string());
+                        break;
+                    }
+                };
+                if message.header.kind != RTN_UNICAST {
+                    continue;
+                }
+                let (destination, prefix) = message.destination_prefix().unwrap_or((unspecified, 0));
+                let Ok(destination) = IpNet::new(destination, prefix) else {
+                    continue;
+                };
+                table.routes.push(RouteEntry {
+                    destination,
+                    gateway: message.gateway(),
+                    ifindex: message.output_interface(),
+                    // routes without a priority have metric 0
+                    metric: Some(message.nlas.iter().find_map(|nla| match nla {
+                        route::Nla::Priority(metric) => Some(*metric),
+                        _ => None,
+                    }).unwrap_or(0)),
+                    table: message.nlas.iter().find_map(|nla| match nla {
+                        route::Nla::Table(table) => Some(*table),
+                        _ => None,
+                    }).unwrap_or(message.header.table.into()),
+                });
+            }
+
+            let mut rules = handle.rule().get(version.clone()).execute();
+            loop {
+                let message = match rules.try_next().await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break,
+                    Err(error) => {
+                        result = Err(error.to_string());
+                        break;
+                    }
+                };
+                // rules that do not look up a table or select by destination, interface, port,
+                // etc. do not apply to the traffic to our peers
+                let selectors = message.nlas.iter().all(|nla| matches!(nla,
+                    rule::Nla::Priority(_) | rule::Nla::Table(_) | rule::Nla::FwMark(_) | rule::Nla::FwMask(_)
+                    | rule::Nla::Source(_) | rule::Nla::SuppressPrefixLen(_) | rule::Nla::Protocol(_)));
+                if (message.header.action != FR_ACT_TO_TBL) || !selectors {
+                    continue;
+                }
+
+                let mut entry = RoutingRule::lookup(0, message.header.table.into());
+                entry.invert = message.header.flags & FIB_RULE_INVERT != 0;
+                let mut mask = u32::MAX;
+                for nla in &message.nlas {
+                    match nla {
+                        rule::Nla::Priority(priority) => entry.priority = *priority,
+                        rule::Nla::Table(table) => entry.table = *table,
+                        rule::Nla::FwMark(mark) => entry.fwmark = Some((*mark, mask)),
+                        rule::Nla::FwMask(value) => mask = *value,
+                        rule::Nla::Source(source) => {
+                            entry.source = Self::address(source).and_then(|source| IpNet::new(source, message.header.src_len).ok());
+                        }
+                        // the kernel sends -1 for rules without suppression
+                        rule::Nla::SuppressPrefixLen(length) => entry.suppress_prefix_length = u8::try_from(*length).ok(),
+                        _ => {}
+                    }
+                }
+                entry.fwmark = entry.fwmark.map(|(mark, _)| (mark, mask));
+                table.rules.push(entry);
+            }
+        }
+
+        connection.abort();
+        result.map(|_| table)
+    }
+}
+
+/// Routes of the main table from the platform routing API, without metrics and rules
+#[cfg(not(target_os = "linux"))]
+pub struct PlatformResolver;
+
+#[cfg(not(target_os = "linux"))]
+impl RouteResolver for PlatformResolver {
+    async fn routing_table(&self) -> Result<RoutingTable, String> {
+        let handle = net_route::Handle::new().map_err(|error| error.to_string())?;
+        let routes = handle.list().await.map_err(|error| error.to_string())?;
+
+        Ok(RoutingTable {
+            routes: routes
+                .into_iter()
+                .filter_map(|route| Some(RouteEntry {
+                    destination: IpNet::new(route.destination, route.prefix).ok()?,
+                    gateway: route.gateway,
+                    ifindex: route.ifindex,
+                    metric: None,
+                    table: TABLE_MAIN,
+                }))
+                .collect(),
+            rules: vec![],
+        })
+    }
+}
+
+/// Resolver of the platform
+#[cfg(target_os = "linux")]
+pub fn system_resolver() -> NetlinkResolver {
+    NetlinkResolver
+}
+
+/// Resolver of the platform
+#[cfg(not(target_os = "linux"))]
+pub fn system_resolver() -> PlatformResolver {
+    PlatformResolver
+}
+
+
+#[cfg(test)]
+mod tests {
+    use if_watch::IpNet;
+
+    use crate::network::routes::{NextHop, RouteEntry, RouteResolver, RoutingRule, RoutingTable, TABLE_MAIN};
+
+    const WLAN: u32 = 2;
+    const ETH: u32 = 3;
+    const WG: u32 = 4;
+
+    fn route(destination: &str, gateway: Option<&str>, ifindex: u32, metric: u32, table: u32) -> RouteEntry {
+        RouteEntry {
+            destination: destination.parse().unwrap(),
+            gateway: gateway.map(|gateway| gateway.parse().unwrap()),
+            ifindex: Some(ifindex),
+            metric: Some(metric),
+            table,
+        }
+    }
+
+    fn net(net: &str) -> IpNet {
+        net.parse().unwrap()
+    }
+
+    /// Dual homed desktop with wifi and ethernet
+    fn dual_homed() -> RoutingTable {
+        RoutingTable {
+            routes: vec![
+                route("0.0.0.0/0", Some("192.168.1.1"), WLAN, 600, TABLE_MAIN),
+                route("0.0.0.0/0", Some("10.0.0.1"), ETH, 100, TABLE_MAIN),
+                route("192.168.1.0/24", None, WLAN, 600, TABLE_MAIN),
+                route("10.0.0.0/24", None, ETH, 100, TABLE_MAIN),
+                route("172.16.0.0/16", Some("10.0.0.254"), ETH, 100, TABLE_MAIN),
+                route("::/0", Some("fe80::1"), WLAN, 1024, TABLE_MAIN),
+            ],
+            rules: vec![],
+        }
+    }
+
+    #[test]
+    fn lowest_metric_is_default() {
+        let table = dual_homed();
+        assert_eq!(table.next_hop(net("10.0.0.5/24"), ETH, 0), NextHop { gateway: Some("10.0.0.1".parse().unwrap()), default: true, metric: Some(100) });
+        assert_eq!(table.next_hop(net("192.168.1.20/24"), WLAN, 0), NextHop { gateway: Some("192.168.1.1".parse().unwrap()), default: false, metric: Some(600) });
+        // the only IPv6 default route
+        assert_eq!(table.next_hop(net("fd00::20/64"), WLAN, 0), NextHop { gateway: Some("fe80::1".parse().unwrap()), default: true, metric: Some(1024) });
+        // network behind a router
+        assert_eq!(table.next_hop(net("172.16.3.4/16"), ETH, 0).gateway, Some("10.0.0.254".parse().unwrap()));
+    }
+
+    #[test]
+    fn no_gateway() {
+        let table = RoutingTable { routes: vec![route("192.168.1.0/24", None, WLAN, 600, TABLE_MAIN)], rules: vec![] };
+        assert_eq!(table.next_hop(net("192.168.1.20/24"), WLAN, 0), NextHop { gateway: None, default: false, metric: Some(600) });
+    }
+
+    #[test]
+    fn wg_quick_policy_routing() {
+        // wg-quick with AllowedIPs = 0.0.0.0/0 and the default fwmark
+        let mut table = dual_homed();
+        table.routes.push(route("0.0.0.0/0", None, WG, 0, 51820));
+        table.rules = vec![
+            RoutingRule { suppress_prefix_length: Some(0), ..RoutingRule::lookup(32764, TABLE_MAIN) },
+            RoutingRule { fwmark: Some((51820, u32::MAX)), invert: true, ..RoutingRule::lookup(32765, 51820) },
+            RoutingRule::lookup(32766, TABLE_MAIN),
+        ];
+
+        // encrypted packets carry the mark and leave over the underlay
+        let hop = table.next_hop(net("10.0.0.5/24"), ETH, 51820);
+        assert_eq!(hop.gateway, Some("10.0.0.1".parse().unwrap()));
+        assert!(hop.default);
+
+        // unmarked packets go through the tunnel, the underlay is not the default anymore
+        let hop = table.next_hop(net("10.0.0.5/24"), ETH, 0);
+        assert_eq!(hop.gateway, Some("10.0.0.1".parse().unwrap()));
+        assert!(!hop.default);
+    }
+
+    #[test]
+    fn source_rules_pick_tables() {
+        // each uplink has its own table selected by source address
+        let table = RoutingTable {
+            routes: vec![
+                route("0.0.0.0/0", Some("10.0.0.1"), ETH, 0, 100),
+                route("0.0.0.0/0", Some("192.168.1.1"), WLAN, 0, 200),
+                route("0.0.0.0/0", Some("10.0.0.1"), ETH, 0, TABLE_MAIN),
+            ],
+            rules: vec![
+                RoutingRule { source: Some(net("192.168.1.0/24")), ..RoutingRule::lookup(100, 200) },
+                RoutingRule { source: Some(net("10.0.0.0/24")), ..RoutingRule::lookup(101, 100) },
+                RoutingRule::lookup(32766, TABLE_MAIN),
+            ],
+        };
+        assert_eq!(table.next_hop(net("192.168.1.20/24"), WLAN, 0), NextHop { gateway: Some("192.168.1.1".parse().unwrap()), default: true, metric: Some(0) });
+        assert!(table.next_hop(net("10.0.0.5/24"), ETH, 0).default);
+    }
+
+    #[tokio::test]
+    async fn resolver() {
+        let hop = dual_homed().next_hop(net("10.0.0.5/24"), ETH, 0);
+        assert_eq!(RouteResolver::next_hop(&dual_homed(), net("10.0.0.5/24"), ETH, 0).await, hop);
+    }
+}
@@ -0,0 +1,295 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::timeout;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const ATTRIBUTE_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTRIBUTE_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Timeouts of the binding request and its retransmissions
+const RETRANSMISSIONS: [Duration; 3] = [Duration::from_millis(500), Duration::from_millis(1000), Duration::from_millis(2000)];
+
+
+/// How the NAT in front of a network maps our local port, see RFC 4787
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NatMapping {
+    /// The external address is the local address, there is no NAT
+    None,
+    /// All STUN servers saw the same external address and port
+    EndpointIndependent,
+    /// STUN servers saw different external ports, peers behind this NAT can not punch holes
+    AddressDependent,
+    /// Only one STUN server answered
+    Unknown,
+}
+
+/// External address of a local network as seen by STUN servers
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct PublicAddress {
+    pub address: SocketAddr,
+    pub mapping: NatMapping,
+}
+
+
+/// Encode a binding request
+fn binding_request(transaction: &[u8; 12]) -> Vec<u8> {
+    let mut data = vec![];
+    data.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes());
+    data.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    data.extend_from_slice(transaction);
+    data
+}
+
+/// Decode the mapped address of a binding response to the request with `transaction`
+fn parse_binding_response(transaction: &[u8; 12], data: &[u8]) -> Result<SocketAddr, String> {
+    if data.len() < 20 {
+        return Err("Truncated STUN response".into());
+    }
+    if (u16::from_be_bytes([data[0], data[1]]) != BINDING_RESPONSE) || (data[4..8] != MAGIC_COOKIE.to_be_bytes()) {
+        return Err("Not a STUN binding response".into());
+    }
+    if &data[8..20] != transaction {
+        return Err("STUN response does not match the request".into());
+    }
+
+    let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let attributes = data.get(20..20 + length).ok_or("Truncated STUN response")?;
+
+    // XOR-MAPPED-ADDRESS wins, some NATs rewrite addresses in the plain MAPPED-ADDRESS
+    let mut mapped = None;
+    let mut pos = 0;
+    while pos + 4 <= attributes.len() {
+        let kind = u16::from_be_bytes([attributes[pos], attributes[pos + 1]]);
+        let size = u16::from_be_bytes([attributes[pos + 2], attributes[pos + 3]]) as usize;
+        let value = attributes.get(pos + 4..pos + 4 + size).ok_or("Truncated STUN attribute")?;
+        match kind {
+            ATTRIBUTE_XOR_MAPPED_ADDRESS => return parse_address(value, Some(&data[4..20])),
+            ATTRIBUTE_MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {}
+        }
+        // attributes are padded to a multiple of four bytes
+        pos += 4 + size.div_ceil(4) * 4;
+    }
+
+    mapped.ok_or("STUN response contains no mapped address".into())
+}
+
+/// Decode a (XOR-)MAPPED-ADDRESS attribute, `xor` holds magic cookie and transaction id
+fn parse_address(value: &[u8], xor: Option<&[u8]>) -> Result<SocketAddr, String> {
+    let mask = |index: usize| xor.map(|xor| xor[index]).unwrap_or(0);
+    if value.len() < 4 {
+        return Err("Truncated STUN address".into());
+    }
+    let port = u16::from_be_bytes([value[2] ^ mask(0), value[3] ^ mask(1)]);
+
+    match (value[1], value.len()) {
+        (0x01, 8) => Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(value[4] ^ mask(0), value[5] ^ mask(1), value[6] ^ mask(2), value[7] ^ mask(3))), port)),
+        (0x02, 20) => {
+            let mut octets = [0u8; 16];
+            for (index, octet) in octets.iter_mut().enumerate() {
+                *octet = value[4 + index] ^ mask(index);
+            }
+            Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        _ => Err("Unknown STUN address family".into()),
+    }
+}
+
+/// Ask one STUN server for the external address of a socket, retransmits lost requests
+async fn binding(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr, String> {
+    let mut transaction = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut transaction);
+    let request = binding_request(&transaction);
+
+    let mut buffer = [0u8; 1500];
+    for wait in RETRANSMISSIONS {
+        socket.send_to(&request, server).await.map_err(|error| format!("{}: {}", server, error))?;
+        let deadline = tokio::time::Instant::now() + wait;
+        // ignore stray packets until the deadline
+        while let Ok(result) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+            let (length, source) = result.map_err(|error| format!("{}: {}", server, error))?;
+            if source != server {
+                continue;
+            }
+            match parse_binding_response(&transaction, &buffer[..length]) {
+                Ok(address) => return Ok(address),
+                Err(error) => debug!("Ignoring STUN packet from {}: {}", server, error),
+            }
+        }
+    }
+
+    Err(format!("STUN server {} did not answer", server))
+}
+
+/// Resolve STUN servers given as `host:port`, only addresses of the same family as `local` are kept
+pub async fn resolve_servers(servers: &[String], local: IpAddr) -> Vec<SocketAddr> {
+    let mut result = vec![];
+    for server in servers {
+        match timeout(Duration::from_secs(2), lookup_host(server.as_str())).await {
+            Ok(Ok(addresses)) => {
+                if let Some(address) = addresses.into_iter().find(|address| address.is_ipv4() == local.is_ipv4()) {
+                    result.push(address);
+                }
+            }
+            Ok(Err(error)) => debug!("Could not resolve STUN server {}: {}", server, error),
+            Err(_) => debug!("Could not resolve STUN server {}: timeout", server),
+        }
+    }
+    result
+}
+
+/// Send the probe out of `device` with the mark of wireguard, a full tunnel of wg-quick routes
+/// every other packet through the tunnel
+#[cfg(target_os = "linux")]
+fn bypass_tunnel(socket: &UdpSocket, device: &str, fwmark: u32) -> Result<(), String> {
+    use std::os::fd::AsRawFd;
+
+    let set = |option: libc::c_int, value: &[u8]| {
+        let result = unsafe { libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, option, value.as_ptr().cast(), value.len() as libc::socklen_t) };
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+    set(libc::SO_BINDTODEVICE, device.as_bytes()).map_err(|error| format!("Could not bind to {}: {}", device, error))?;
+    if fwmark != 0 {
+        set(libc::SO_MARK, &fwmark.to_ne_bytes()).map_err(|error| format!("Could not set fwmark {}: {}", fwmark, error))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bypass_tunnel(_socket: &UdpSocket, _device: &str, _fwmark: u32) -> Result<(), String> {
+    Ok(())
+}
+
+/// Find the external address of a local address on `device` and the mapping behavior of the NAT in
+/// front of it, the same local port is used for all servers so the mapped ports can be compared.
+/// The listen port of wireguard is taken by the kernel, so the mapped port is the one of the probe
+/// socket and only matches wireguard without NAT.
+pub async fn probe(local: IpAddr, device: &str, fwmark: u32, servers: &[SocketAddr]) -> Result<PublicAddress, String> {
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await.map_err(|error| format!("{}: {}", local, error))?;
+    bypass_tunnel(&socket, device, fwmark)?;
+    let local_address = socket.local_addr().map_err(|error| error.to_string())?;
+
+    let mut mapped: Vec<SocketAddr> = vec![];
+    let mut errors: Vec<String> = vec![];
+    for server in servers {
+        match binding(&socket, *server).await {
+            Ok(address) => mapped.push(address),
+            Err(error) => errors.push(error),
+        }
+    }
+
+    let Some(address) = mapped.first().copied() else {
+        return Err(if errors.is_empty() { "No STUN server".into() } else { errors.join(", ") });
+    };
+    let mapping = if address == local_address {
+        NatMapping::None
+    } else if mapped.len() < 2 {
+        NatMapping::Unknown
+    } else if mapped.iter().all(|item| *item == address) {
+        NatMapping::EndpointIndependent
+    } else {
+        NatMapping::AddressDependent
+    };
+
+    Ok(PublicAddress { address, mapping })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use tokio::net::UdpSocket;
+
+    use crate::network::stun::{binding_request, parse_binding_response, probe, NatMapping, ATTRIBUTE_XOR_MAPPED_ADDRESS, BINDING_RESPONSE, MAGIC_COOKIE};
+
+    /// Binding response with a XOR-MAPPED-ADDRESS attribute
+    fn binding_response(transaction: &[u8], address: SocketAddr) -> Vec<u8> {
+        let mut xor = MAGIC_COOKIE.to_be_bytes().to_vec();
+        xor.extend_from_slice(transaction);
+
+        let mut value = vec![0u8];
+        let octets = match address.ip() {
+            IpAddr::V4(ip) => {
+                value.push(0x01);
+                ip.octets().to_vec()
+            }
+            IpAddr::V6(ip) => {
+                value.push(0x02);
+                ip.octets().to_vec()
+            }
+        };
+        let port = address.port().to_be_bytes();
+        value.extend_from_slice(&[port[0] ^ xor[0], port[1] ^ xor[1]]);
+        value.extend(octets.iter().enumerate().map(|(index, octet)| octet ^ xor[index]));
+
+        let mut data = BINDING_RESPONSE.to_be_bytes().to_vec();
+        data.extend_from_slice(&((value.len() + 4) as u16).to_be_bytes());
+        data.extend_from_slice(&xor);
+        data.extend_from_slice(&ATTRIBUTE_XOR_MAPPED_ADDRESS.to_be_bytes());
+        data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        data.extend_from_slice(&value);
+        data
+    }
+
+    /// Local stand-in for a STUN server, answers with the source address or a fixed one
+    async fn stun_server(mapped: Option<SocketAddr>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((_, source)) = socket.recv_from(&mut buffer).await {
+                let response = binding_response(&buffer[8..20], mapped.unwrap_or(source));
+                socket.send_to(&response, source).await.unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let transaction = [7u8; 12];
+        for address in ["203.0.113.5:40000", "[2001:db8::5]:40000"] {
+            let address: SocketAddr = address.parse().unwrap();
+            assert_eq!(parse_binding_response(&transaction, &binding_response(&transaction, address)), Ok(address));
+        }
+        assert!(parse_binding_response(&[8u8; 12], &binding_response(&transaction, "203.0.113.5:1".parse().unwrap())).is_err());
+        assert!(parse_binding_response(&transaction, &binding_request(&transaction)).is_err());
+    }
+
+    #[tokio::test]
+    async fn no_nat() {
+        let servers = vec![stun_server(None).await, stun_server(None).await];
+        let public = probe("127.0.0.1".parse().unwrap(), "lo", 0, &servers).await.unwrap();
+        assert_eq!(public.address.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(public.mapping, NatMapping::None);
+    }
+
+    #[tokio::test]
+    async fn mapping_behavior() {
+        let first: SocketAddr = "203.0.113.5:40000".parse().unwrap();
+        let second: SocketAddr = "203.0.113.5:40001".parse().unwrap();
+
+        let servers = vec![stun_server(Some(first)).await, stun_server(Some(first)).await];
+        assert_eq!(probe("127.0.0.1".parse().unwrap(), "lo", 0, &servers).await.unwrap().mapping, NatMapping::EndpointIndependent);
+
+        let servers = vec![stun_server(Some(first)).await, stun_server(Some(second)).await];
+        let public = probe("127.0.0.1".parse().unwrap(), "lo", 0, &servers).await.unwrap();
+        assert_eq!(public.address, first);
+        assert_eq!(public.mapping, NatMapping::AddressDependent);
+
+        let servers = vec![stun_server(Some(first)).await];
+        assert_eq!(probe("127.0.0.1".parse().unwrap(), "lo", 0, &servers).await.unwrap().mapping, NatMapping::Unknown);
+    }
+}
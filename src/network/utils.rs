@@ -1,7 +1,9 @@
 use if_watch::IpNet;
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
 use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
-use net_route::Handle;
+
+use super::routes::{system_resolver, NextHop, RouteResolver};
+
 
 
 pub trait GetInterface {
@@ -64,45 +66,40 @@ impl FirstIp for IpNet {
     }    
 }
 
-// TODO: Make this a trait
-pub async fn next_hop(net: IpNet) -> (bool, Option<IpAddr>) {
-    match Handle::new() {
-        Ok(handle) => {
-            if let Ok(routes) = handle.list().await {
-                // Find next hop
-                for route in &routes {
-                    if (net.prefix_len() == route.prefix) && (net.contains(&route.destination)) {
-                        if let Some(_) = route.gateway {
-                            return (false, route.gateway.clone());
-                        }
+/// List all non-loopback networks that are currently configured on this machine
+pub fn enumerate_networks() -> Vec<IpNet> {
+    let mut result: Vec<IpNet> = vec![];
+
+    match NetworkInterface::show() {
+        Ok(interfaces) => {
+            for interface in &interfaces {
+                for addr in &interface.addr {
+                    if addr.ip().is_loopback() {
+                        continue;
                     }
-                }
-                // No next hop, find standard gateway
-                if let Some(interface) = net.interface() {
-                    for route in &routes {
-                        if (route.prefix != 0) || (route.ifindex.unwrap() != interface.index) {
-                            continue;
-                        }
-                        if let Some(gateway) = route.gateway {
-                            if let (IpNet::V4(_), IpAddr::V4(_)) = (net, gateway) {
-                                return (true, route.gateway.clone());
-                            }
-                            if let (IpNet::V6(_), IpAddr::V6(_)) = (net, gateway) {
-                                return (true, route.gateway.clone());
-                            }
+                    if let Some(netmask) = addr.netmask() {
+                        if let Ok(net) = IpNet::with_netmask(addr.ip(), netmask) {
+                            result.push(net);
                         }
-                    }                        
+                    }
                 }
             }
         }
         Err(error) => {
-            error!("Could not fetch routing table: {:?}", error);
+            error!("Could not list network interfaces: {:?}", error);
         }
     }
-    
-    (false, None)
+
+    result
 }
 
+/// Next hop of a network on its interface for packets with `fwmark`, see `RoutingTable::next_hop`
+pub async fn next_hop(net: IpNet, fwmark: u32) -> NextHop {
+    match net.interface() {
+        Some(interface) => system_resolver().next_hop(net, interface.index, fwmark).await,
+        None => NextHop::default(),
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -124,5 +121,4 @@ mod tests {
         assert_eq!(IpNet::from_str("fd12:3456:789a:1::/64").unwrap().first_ip(), "fd12:3456:789a:1::1".parse::<IpAddr>().unwrap());
         assert_eq!(IpNet::from_str("fd12:3456:789a:1::/8").unwrap().first_ip(), "fd00::1".parse::<IpAddr>().unwrap());
     }
-
-}
\ No newline at end of file
+}
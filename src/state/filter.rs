@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+/// Selects interfaces by name, patterns may contain `*` for any number of characters and `?` for
+/// one character. An empty include list selects all interfaces, excludes always win.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct InterfaceFilter {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl InterfaceFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|pattern| glob_match(pattern, name));
+        included && !self.exclude.iter().any(|pattern| glob_match(pattern, name))
+    }
+}
+
+/// Match a name against a pattern with `*` and `?` wildcards
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // position after the last star in pattern and the name position it matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last star eat one more character
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::state::filter::{glob_match, InterfaceFilter};
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("wg0", "wg0"));
+        assert!(!glob_match("wg0", "wg01"));
+        assert!(glob_match("wg*", "wg-site"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("wg?", "wg1"));
+        assert!(!glob_match("wg?", "wg"));
+        assert!(glob_match("*-s2s-*", "wg-s2s-berlin"));
+        assert!(!glob_match("*-s2s-*", "wg-s2s"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let all = InterfaceFilter::default();
+        assert!(all.matches("docker0"));
+
+        let filter = InterfaceFilter { include: vec!["wg*".into()], exclude: vec!["wg-s2s*".into()] };
+        assert!(filter.matches("wg0"));
+        assert!(!filter.matches("wg-s2s-office"));
+        assert!(!filter.matches("tun0"));
+
+        let filter = InterfaceFilter { include: vec![], exclude: vec!["docker*".into(), "virbr?".into(), "tailscale0".into()] };
+        assert!(filter.matches("eth0"));
+        assert!(!filter.matches("docker0"));
+        assert!(!filter.matches("virbr0"));
+        assert!(!filter.matches("tailscale0"));
+    }
+}
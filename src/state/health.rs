@@ -0,0 +1,162 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::structs::{Peer, PeerStats};
+
+/// Longest time a failed peer is not retried, in seconds
+const MAX_RETRY_DELAY: u64 = 3600;
+
+/// Current unix timestamp in seconds
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default()
+}
+
+/// State of the direct connection to a peer
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerHealth {
+    /// No handshake yet, but still within the handshake timeout or no traffic was sent
+    Pending,
+    /// A handshake succeeded over the direct path
+    Connected,
+    /// Traffic was sent but no handshake happened within the handshake timeout
+    Failed,
+}
+
+impl fmt::Display for PeerHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerHealth::Pending => write!(f, "pending"),
+            PeerHealth::Connected => write!(f, "connected"),
+            PeerHealth::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Health of the direct connection to a managed peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthRecord {
+    pub interface: String,
+    pub pubkey: String,
+    pub endpoint: Option<IpAddr>,
+    pub health: PeerHealth,
+    /// Unix timestamp of when the peer was added
+    pub added: u64,
+    /// Unix timestamp of the first handshake after the peer was added
+    pub handshake: Option<u64>,
+    /// Received bytes when the peer was added
+    pub rx_baseline: u64,
+    /// Transmitted bytes when the peer was added, used to detect if we tried to send anything
+    pub tx_baseline: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Number of times the direct connection failed in a row
+    #[serde(default)]
+    pub failures: u32,
+    /// Unix timestamp after which a failed peer may be added again
+    #[serde(default)]
+    pub retry_at: Option<u64>,
+}
+
+impl HealthRecord {
+    pub fn new(interface: String, pubkey: String, endpoint: Option<IpAddr>, added: u64, stats: &PeerStats) -> Self {
+        Self {
+            interface,
+            pubkey,
+            endpoint,
+            health: PeerHealth::Pending,
+            added,
+            handshake: None,
+            rx_baseline: stats.rx_bytes,
+            tx_baseline: stats.tx_bytes,
+            rx_bytes: stats.rx_bytes,
+            tx_bytes: stats.tx_bytes,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    /// Check if the record keeps a peer from being added again, failed peers are retried after a
+    /// delay that doubles with every failure
+    pub fn blocks(&self, peer: &Peer, now: u64) -> bool {
+        (self.health == PeerHealth::Failed) && self.matches(peer) && self.retry_at.is_none_or(|retry_at| now < retry_at)
+    }
+
+    /// Check if the record belongs to a peer with the same endpoint
+    pub fn matches(&self, peer: &Peer) -> bool {
+        (Some(&self.interface) == peer.wg_interface.as_ref()) && (self.pubkey == peer.pubkey) && (self.endpoint == peer.endpoint)
+    }
+
+    /// Update the record from the current wireguard statistics, pending peers become connected
+    /// after a handshake or failed if we sent traffic without a handshake for `timeout` seconds
+    pub fn update(&mut self, stats: &PeerStats, now: u64, timeout: u64) {
+        self.rx_bytes = stats.rx_bytes;
+        self.tx_bytes = stats.tx_bytes;
+
+        if self.health != PeerHealth::Pending {
+            return;
+        }
+
+        if stats.last_handshake >= self.added {
+            self.handshake = Some(stats.last_handshake);
+            self.health = PeerHealth::Connected;
+            self.failures = 0;
+        } else if (now >= self.added + timeout) && (stats.tx_bytes > self.tx_baseline) {
+            self.health = PeerHealth::Failed;
+            self.failures += 1;
+            let delay = timeout.saturating_mul(1 << self.failures.min(16)).min(MAX_RETRY_DELAY);
+            self.retry_at = Some(now + delay);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::state::health::{HealthRecord, PeerHealth};
+    use crate::state::testing::{peer, stats};
+
+    #[test]
+    fn handshake_connects() {
+        let mut record = HealthRecord::new("wg0".into(), "a".into(), None, 1000, &stats(0, 100));
+        record.update(&stats(0, 200), 1010, 30);
+        assert_eq!(record.health, PeerHealth::Pending);
+        record.update(&stats(1012, 300), 1015, 30);
+        assert_eq!(record.health, PeerHealth::Connected);
+        assert_eq!(record.handshake, Some(1012));
+    }
+
+    #[test]
+    fn no_handshake_fails_after_timeout() {
+        let mut record = HealthRecord::new("wg0".into(), "a".into(), None, 1000, &stats(500, 100));
+        record.update(&stats(500, 200), 1029, 30);
+        assert_eq!(record.health, PeerHealth::Pending);
+        record.update(&stats(500, 200), 1030, 30);
+        assert_eq!(record.health, PeerHealth::Failed);
+    }
+
+    #[test]
+    fn failed_peer_is_retried_later() {
+        let peer = peer("a", "192.168.1.2");
+        let mut record = HealthRecord::new("wg0".into(), "a".into(), peer.endpoint, 1000, &stats(500, 100));
+        assert!(!record.blocks(&peer, 1000));
+        record.update(&stats(500, 200), 1030, 30);
+        assert!(record.blocks(&peer, 1089));
+        assert!(!record.blocks(&peer, 1090));
+
+        // the delay doubles with every failure
+        let mut record = HealthRecord { failures: record.failures, ..HealthRecord::new("wg0".into(), "a".into(), peer.endpoint, 2000, &stats(500, 100)) };
+        record.update(&stats(500, 200), 2030, 30);
+        assert_eq!(record.retry_at, Some(2150));
+    }
+
+    #[test]
+    fn idle_peer_stays_pending() {
+        let mut record = HealthRecord::new("wg0".into(), "a".into(), None, 1000, &stats(0, 100));
+        record.update(&stats(0, 100), 2000, 30);
+        assert_eq!(record.health, PeerHealth::Pending);
+    }
+}
@@ -0,0 +1,167 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::health::unix_time;
+use super::structs::{OriginalPeer, Peer};
+
+/// A peer that has been added to a wireguard interface by this daemon
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub interface: String,
+    pub pubkey: String,
+    pub endpoint: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub allowed_ips: Option<Vec<IpAddr>>,
+    #[serde(default)]
+    pub scope_id: Option<u32>,
+    /// Unix timestamp of when the peer was added
+    pub added: u64,
+    /// Configuration to restore when the peer is withdrawn, `None` if the peer did not exist before
+    #[serde(default)]
+    pub original: Option<OriginalPeer>,
+}
+
+impl From<&JournalEntry> for Peer {
+    fn from(value: &JournalEntry) -> Self {
+        Peer {
+            pubkey: value.pubkey.clone(),
+            endpoint: value.endpoint,
+            port: value.port,
+            ip: value.allowed_ips.clone(),
+            wg_interface: Some(value.interface.clone()),
+            scope_id: value.scope_id,
+            local_ip: None,
+        }
+    }
+}
+
+/// On-disk list of all peers this daemon added, survives crashes
+#[derive(Clone, Debug, Default)]
+pub struct Journal {
+    path: Option<PathBuf>,
+    entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    /// Load the journal, a missing or broken file results in an empty journal
+    pub fn load(path: PathBuf) -> Self {
+        let entries = match fs::read_to_string(&path) {
+            Ok(content) => match serde_json::from_str::<Vec<JournalEntry>>(&content) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    error!("Ignoring broken state file {}: {}", path.display(), error);
+                    vec![]
+                }
+            },
+            Err(_) => vec![],
+        };
+
+        Self { path: Some(path), entries }
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Find the entry of a peer on a wireguard interface
+    pub fn find(&self, interface: &str, pubkey: &str) -> Option<&JournalEntry> {
+        self.entries.iter().find(|item| (item.interface == interface) && (item.pubkey == pubkey))
+    }
+
+    /// Remember a peer that has been added to a wireguard interface
+    pub fn record(&mut self, peer: &Peer, original: Option<OriginalPeer>) {
+        let interface = peer.wg_interface.clone().unwrap_or_default();
+        self.entries.retain(|item| (item.interface != interface) || (item.pubkey != peer.pubkey));
+        self.entries.push(JournalEntry {
+            interface,
+            pubkey: peer.pubkey.clone(),
+            endpoint: peer.endpoint,
+            port: peer.port,
+            allowed_ips: peer.ip.clone(),
+            scope_id: peer.scope_id,
+            added: unix_time(),
+            original,
+        });
+        self.save();
+    }
+
+    /// Forget a peer that has been removed from a wireguard interface
+    pub fn forget(&mut self, peer: &Peer) {
+        let interface = peer.wg_interface.clone().unwrap_or_default();
+        let count = self.entries.len();
+        self.entries.retain(|item| (item.interface != interface) || (item.pubkey != peer.pubkey));
+        if count != self.entries.len() {
+            self.save();
+        }
+    }
+
+    /// Write the journal to disk, replaces the old file atomically
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            let result = serde_json::to_string_pretty(&self.entries)
+                .map_err(|error| error.to_string())
+                .and_then(|data| write_private(path, data.as_bytes()));
+            if let Err(error) = result {
+                error!("Could not write state file {}: {}", path.display(), error);
+            }
+        }
+    }
+}
+
+/// Replace `path` with a file only the owner can read, the temporary file is created new so it
+/// never follows a symlink somebody else placed there. Other systems keep the default permissions.
+fn write_private(path: &Path, data: &[u8]) -> Result<(), String> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder.create(dir).map_err(|error| error.to_string())?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    // left over by a crash
+    let _ = fs::remove_file(&tmp_path);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp_path).map_err(|error| error.to_string())?;
+    file.write_all(data).and_then(|_| file.sync_all()).map_err(|error| error.to_string())?;
+    fs::rename(&tmp_path, path).map_err(|error| error.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::state::journal::Journal;
+    use crate::state::structs::Peer;
+    use crate::state::testing::peer;
+
+    #[test]
+    fn journal_survives_reload() {
+        let path = std::env::temp_dir().join(format!("wireguard-web-autopeer-journal-{}.json", std::process::id()));
+
+        let mut journal = Journal::load(path.clone());
+        journal.record(&peer("a", "192.168.1.2"), None);
+        journal.record(&peer("b", "192.168.1.3"), None);
+        journal.record(&peer("a", "192.168.1.4"), None);
+        journal.forget(&peer("b", "192.168.1.3"));
+
+        let journal = Journal::load(path.clone());
+        let peers: Vec<Peer> = journal.entries().iter().map(Peer::from).collect();
+        assert_eq!(peers, vec![peer("a", "192.168.1.4")]);
+        #[cfg(unix)]
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,12 +1,18 @@
-use if_watch::IpNet;
+use crate::network::monitor::NetworkChange;
+
+use super::query::QueryResults;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Message{
-    InterfaceUp(IpNet),
-    InterfaceDown(IpNet),
+    /// Addresses changed and settled again
+    NetworkChanged(NetworkChange),
+    /// Routes outside of the local table changed and settled again
+    RoutesChanged,
     RefreshPeers,
     Suspend,
     Resume,
     Quit,
-    InterfacesLoaded,
+    CheckHealth,
+    /// Results of a query round running in the background
+    QueriesFinished(Box<QueryResults>),
 }
\ No newline at end of file
@@ -1,44 +1,259 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use if_watch::IpNet;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
 
-use crate::{network::utils::{GetInterface, next_hop}, wireguard::information::{query_wg_info, add_peer, remove_peer}, http::peering::peering_request};
+use crate::{network::utils::{GetInterface, next_hop}, wireguard::{backend::{blocking, SystemBackend}, error::WireguardError, information::{query_wg_info, list_peers, peer_allowed_ips, get_peer, peer_stats, add_peer, remove_peer, restore_peer}}, http::{client::CircuitState, peering::PeeringReport}};
 
-use self::structs::{StateManager, Settings, NetworkInterface, Peer};
+use self::health::{HealthRecord, PeerHealth, unix_time};
+use self::journal::Journal;
+use self::messages::Message;
+use self::policy::{PeerPolicy, StaticPeer};
+use self::query::{QueryContext, QueryResults, QueryRound, ReportRound};
+use self::structs::{StateManager, Settings, NetworkInterface, Peer, ReconcileMode, is_link_local, select_endpoints};
 
 pub mod structs;
 pub mod messages;
+pub mod status;
+pub mod journal;
+pub mod health;
+pub mod filter;
+pub mod query;
+pub mod policy;
+#[cfg(test)]
+pub mod testing;
 
 impl StateManager {
-    pub fn new() -> Self {
+    pub fn new(settings: Settings) -> Self {
         Self{
             interfaces: vec![],
-            settings: Settings::default(),
-            suspended: true
+            journal: Journal::load(settings.state_file_path()),
+            settings,
+            suspended: true,
+            dry_run: false,
+            health: vec![],
+            connections: HashMap::new(),
+            breakers: HashMap::new(),
+            public_addresses: HashMap::new(),
+            generation: 0,
+            backend: Arc::new(SystemBackend::default()),
+            signed: Default::default(),
+        }
+    }
+
+    /// Copy of the state a round in the background needs
+    pub fn context(&self) -> QueryContext {
+        QueryContext {
+            settings: self.settings.clone(),
+            interfaces: self.interfaces.clone(),
+            public_addresses: self.public_addresses.clone(),
+            backend: self.backend.clone(),
+            signed: self.signed.clone(),
+        }
+    }
+
+    /// Check if a wireguard interface gets peering queries, site to site tunnels can be excluded in the settings
+    pub fn is_managed(&self, interface: &NetworkInterface) -> bool {
+        interface.has_pubkey() && self.settings.wireguard_interfaces.matches(&interface.name)
+    }
+
+    /// Prepare a query round on all managed wireguard interfaces, interfaces whose circuit is open
+    /// are skipped. Every round supersedes the ones before it. Returns `None` while suspended.
+    pub fn prepare_queries(&mut self) -> Option<QueryRound> {
+        if self.suspended {
+            return None;
+        }
+
+        self.generation += 1;
+        let now = unix_time();
+        let mut queries = vec![];
+        let mut skipped = 0;
+        for interface in self.interfaces.clone() {
+            if !self.is_managed(&interface) {
+                continue;
+            }
+            let breaker = self.breakers.entry(interface.name.clone()).or_default();
+            if !breaker.allow(now) {
+                warn!("Skipping peering query on interface {}: {}", interface.name, breaker);
+                skipped += 1;
+                continue;
+            }
+            let cached = self.connections.get(&interface.name).cloned();
+            queries.push((interface, cached));
         }
+
+        Some(QueryRound {
+            generation: self.generation,
+            started: now,
+            context: self.context(),
+            queries,
+            skipped,
+        })
     }
 
-    async fn perform_queries(&mut self) {
-        // Create a peering queries
-        if !self.suspended {
-            for interface in self.interfaces.clone() {
-                if interface.has_pubkey() {
-                    info!("Performing peering query on interface {} for {}...", interface.name, interface.net.unwrap());
-                    match peering_request(&self, &interface).await {
-                        Ok(response) => self.update_peers(response.peers, &interface),
-                        Err(error) => error!("ERROR: {}", error),
+    /// Apply the results of a query round, results of a superseded round are discarded.
+    /// Returns the number of performed and failed queries, skipped queries count as failed.
+    pub async fn apply_queries(&mut self, results: QueryResults) -> (usize, usize) {
+        if results.generation != self.generation {
+            debug!("Discarding results of superseded query round {}", results.generation);
+            return (0, 0);
+        }
+
+        self.public_addresses = results.public_addresses;
+        for (name, net, fingerprint) in results.fingerprints {
+            if let Some(interface) = self.interfaces.iter_mut().find(|item| (item.name == name) && (item.net == net)) {
+                interface.fingerprint = fingerprint;
+            }
+        }
+
+        let mut performed = results.skipped;
+        let mut failed = results.skipped;
+        for outcome in results.outcomes {
+            let wg = outcome.interface;
+            // the interface went down while the query was running
+            if !self.interfaces.iter().any(|item| (item.name == wg.name) && (item.net == wg.net)) {
+                debug!("Discarding peering response of interface {} for {:?}, the network is gone", wg.name, wg.net);
+                continue;
+            }
+            performed += 1;
+
+            if let Some(connection) = outcome.connection {
+                self.connections.insert(wg.name.clone(), connection);
+            }
+            let breaker = self.breakers.entry(wg.name.clone()).or_default();
+            match outcome.result {
+                Ok(peers) => {
+                    breaker.success();
+                    self.update_peers(peers, &wg).await;
+                }
+                Err(error) => {
+                    error!("ERROR: {}", error);
+                    breaker.failure(error, results.started, &self.settings.http);
+                    if breaker.state == CircuitState::Open {
+                        warn!("Pausing peering queries on interface {}: {}", wg.name, breaker);
                     }
+                    failed += 1;
                 }
             }
         }
+
+        (performed, failed)
+    }
+
+    /// Run a query round in a background task, its results come back as `Message::QueriesFinished`
+    pub fn spawn_queries(&mut self, tx: Sender<Message>) -> Option<JoinHandle<()>> {
+        let round = self.prepare_queries()?;
+        debug!("Starting query round {}", round.generation);
+        Some(tokio::spawn(async move {
+            let results = round.run().await;
+            // the receiver is gone when shutting down
+            let _ = tx.send(Message::QueriesFinished(Box::new(results))).await;
+        }))
+    }
+
+    /// Run peering queries on all wireguard interfaces and wait for them, returns the number of performed and failed queries
+    async fn perform_queries(&mut self) -> (usize, usize) {
+        match self.prepare_queries() {
+            Some(round) => {
+                let results = round.run().await;
+                self.apply_queries(results).await
+            }
+            None => (0, 0),
+        }
+    }
+
+    /// Add a peer to its wireguard interface, in dry run mode only log what would be done.
+    /// Returns false if the peer could not be added.
+    async fn install_peer(&mut self, peer: &Peer) -> bool {
+        let wg_interface = peer.wg_interface.clone().unwrap_or_default();
+        if self.dry_run {
+            info!("Dry run: would add peer {} @ {:?} to interface {}", peer.pubkey, peer.endpoint, wg_interface);
+            return true;
+        }
+
+        // snapshot statically configured peers before touching them, a managed peer keeps its first snapshot
+        let original = match self.journal.find(&wg_interface, &peer.pubkey) {
+            Some(entry) => entry.original.clone(),
+            None => {
+                let (name, pubkey) = (wg_interface.clone(), peer.pubkey.clone());
+                let original = blocking(&self.backend, move |backend| get_peer(backend, &name, &pubkey)).await;
+                if original.is_some() {
+                    info!("Peer {} is already configured on interface {}, saving its configuration", peer.pubkey, wg_interface);
+                }
+                original
+            }
+        };
+
+        let (name, added) = (wg_interface.clone(), peer.clone());
+        match blocking(&self.backend, move |backend| add_peer(backend, &name, &added)).await {
+            Ok(_) => {
+                info!("Added peer {} @ {:?} to interface {}", peer.pubkey, peer.endpoint, wg_interface);
+                self.journal.record(peer, original);
+
+                // start watching the handshake, a retried peer keeps its failures for the backoff
+                let failures = self.health.iter().find(|record| record.matches(peer)).map(|record| record.failures).unwrap_or_default();
+                self.health.retain(|record| (Some(&record.interface) != peer.wg_interface.as_ref()) || (record.pubkey != peer.pubkey));
+                let (name, pubkey) = (wg_interface.clone(), peer.pubkey.clone());
+                if let Some(stats) = blocking(&self.backend, move |backend| peer_stats(backend, &name, &pubkey)).await {
+                    let record = HealthRecord::new(wg_interface, peer.pubkey.clone(), peer.endpoint, unix_time(), &stats);
+                    self.health.push(HealthRecord { failures, ..record });
+                }
+                true
+            }
+            Err(error) => {
+                error!("Error adding peer {} @ {:?} to interface {}: {}", peer.pubkey, peer.endpoint, wg_interface, error);
+                false
+            }
+        }
+    }
+
+    /// Remove a peer from its wireguard interface or restore the configuration it had before
+    /// it was added, in dry run mode only log what would be done
+    async fn withdraw_peer(&mut self, peer: &Peer) {
+        let wg_interface = peer.wg_interface.clone().unwrap_or_default();
+        let original = self.journal.find(&wg_interface, &peer.pubkey).and_then(|entry| entry.original.clone());
+
+        if let Some(original) = original {
+            if self.dry_run {
+                info!("Dry run: would restore original configuration of peer {} on interface {}", peer.pubkey, wg_interface);
+                return;
+            }
+            let (name, pubkey) = (wg_interface.clone(), peer.pubkey.clone());
+            match blocking(&self.backend, move |backend| restore_peer(backend, &name, &pubkey, &original)).await {
+                Ok(_) => {
+                    info!("Restored original configuration of peer {} on interface {}", peer.pubkey, wg_interface);
+                    self.journal.forget(peer);
+                }
+                Err(error) => error!("Error restoring peer {} on interface {}: {}", peer.pubkey, wg_interface, error),
+            }
+            return;
+        }
+
+        if self.dry_run {
+            info!("Dry run: would remove peer {} @ {:?} from interface {}", peer.pubkey, peer.endpoint, wg_interface);
+            return;
+        }
+        let (name, removed) = (wg_interface.clone(), peer.clone());
+        match blocking(&self.backend, move |backend| remove_peer(backend, &name, &removed)).await {
+            Ok(_) => {
+                info!("Removed peer {} @ {:?} from interface {}", peer.pubkey, peer.endpoint, wg_interface);
+                self.journal.forget(peer);
+            }
+            Err(error) => error!("Error removing peer {} @ {:?} from interface {}: {}", peer.pubkey, peer.endpoint, wg_interface, error),
+        }
     }
 
     fn add_or_update_interface(&mut self, interface: NetworkInterface) -> bool {
         // find interface
-        for mut item in &mut self.interfaces {
+        for item in &mut self.interfaces {
             if (item.name == interface.name) && (item.net == interface.net) {
-                if (item.nexthop != interface.nexthop) || (item.is_default != interface.is_default) || (item.wireguard != interface.wireguard) {
+                if (item.nexthop != interface.nexthop) || (item.is_default != interface.is_default) || (item.metric != interface.metric) || (item.wireguard != interface.wireguard) {
                     debug!("Updating Interface: {:?}", interface);
                     item.nexthop = interface.nexthop;
                     item.is_default = interface.is_default;
+                    item.metric = interface.metric;
                     item.wireguard = interface.wireguard.clone();
                     return true;
                 }
@@ -51,33 +266,92 @@ impl StateManager {
         true
     }
 
+    /// Peers on a wireguard interface that autopeering did not add, peers it took over count with
+    /// their original allowed ips
+    async fn static_peers(&self, wg_interface: &str) -> Result<Vec<StaticPeer>, WireguardError> {
+        let name = wg_interface.to_string();
+        let peers = blocking(&self.backend, move |backend| peer_allowed_ips(backend, &name)).await?;
+        Ok(peers
+            .into_iter()
+            .filter_map(|(pubkey, allowed_ips)| match self.journal.find(wg_interface, &pubkey) {
+                None => Some(StaticPeer { pubkey, allowed_ips }),
+                Some(entry) => entry.original.as_ref().map(|original| StaticPeer {
+                    allowed_ips: original.allowed_ips.iter().filter_map(|net| net.parse().ok()).collect(),
+                    pubkey,
+                }),
+            })
+            .collect())
+    }
+
     /// update peers of an interface, returns peers that have been removed
-    fn update_peers(&mut self, peers: Vec<Peer>, wg: &NetworkInterface) {
+    async fn update_peers(&mut self, peers: Vec<Peer>, wg: &NetworkInterface) {
         let mut old_peers: Vec<Peer> = vec![];
+        let mut new_peers: Vec<Peer> = vec![];
+
+        // never trust the server with the device, without the static peers nothing can be checked
+        let static_peers = match self.static_peers(&wg.name).await {
+            Ok(static_peers) => static_peers,
+            Err(error) => {
+                error!("Ignoring peering response of interface {}, could not read its peers: {}", wg.name, error);
+                return;
+            }
+        };
+        let policy = PeerPolicy {
+            wg,
+            interfaces: &self.interfaces,
+            underlay: &self.settings.underlay_interfaces,
+            static_peers,
+            managed: self.interfaces.iter().flat_map(|item| &item.peers).filter(|peer| peer.wg_interface.as_ref() == Some(&wg.name)).cloned().collect(),
+            server: self.connections.get(&wg.name).and_then(|connection| connection.endpoint.address),
+        };
+        let peers = policy.sanitise(peers);
 
         for interface in &self.interfaces {
             old_peers.extend(interface.peers.clone());
         }
 
-        // find correct network interface for peers
-        for peer in &peers {
+        // forget failed peers the server does not offer anymore
+        self.health.retain(|record| (record.health != PeerHealth::Failed) || (record.interface != wg.name) || peers.iter().any(|peer| record.matches(peer)));
+
+        // find correct network interface for peers, peers that could not be reached directly are retried later
+        let now = unix_time();
+        let failed = |peer: &Peer| {
+            let failed = self.health.iter().any(|record| record.blocks(peer, now));
+            if failed {
+                debug!("Skipping peer {} @ {:?}, direct connection failed before", peer.pubkey, peer.endpoint);
+            }
+            failed
+        };
+        let mut selected = select_endpoints(&peers, &self.interfaces, &self.settings.underlay_interfaces, failed);
+
+        // link local endpoints are only unique together with the interface
+        for (peer, index) in &mut selected {
+            if peer.endpoint.is_some_and(|endpoint| is_link_local(&endpoint)) {
+                peer.scope_id = self.interfaces[*index].net.and_then(|net| net.interface()).map(|netif| netif.index);
+            }
+        }
+        let peers: Vec<Peer> = selected.iter().map(|(peer, _)| peer.clone()).collect();
+
+        for (peer, index) in selected {
+            if self.interfaces[index].peers.contains(&peer) {
+                continue;
+            }
+
+            // a peer that moved to a better network is already installed, only record peers that
+            // are on the device
+            let installed = self.interfaces.iter().any(|item| item.peers.contains(&peer));
+            if !installed && !self.install_peer(&peer).await {
+                continue;
+            }
             for interface in &mut self.interfaces {
-                if let (Some(net), Some(endpoint)) = (interface.net, peer.endpoint) {
-                    // check if the network contains the endpoint and if the do not have the peer already
-                    if net.contains(&endpoint) {
-                        if !interface.peers.contains(peer) {
-                            interface.peers.push(peer.clone());
-                            // add peers to wireguard interface
-                            match add_peer(peer.clone()) {
-                                Ok(_) => info!("Added peer {:?} @ {} to interface {:?}", endpoint, wg.name, net),
-                                Err(error) => error!("Error adding peer {:?} @ {} to interface {:?}: {:?}", endpoint, wg.name, net, error),
-                            }
-                        }
-
-                        // only add peer to first interface
-                        break;
-                    }
-                }
+                interface.peers.retain(|item| item != &peer);
+            }
+
+            let interface = &mut self.interfaces[index];
+            debug!("Peer {:?} @ {} is reachable on interface {} {:?}", peer.endpoint, wg.name, interface.name, interface.net);
+            interface.peers.push(peer.clone());
+            if !installed {
+                new_peers.push(peer);
             }
         }
 
@@ -88,51 +362,79 @@ impl StateManager {
                     return false;
                 }
             }
-            return !peers.contains(peer);
+            !peers.contains(peer)
         });
 
         for interface in &mut self.interfaces {
             interface.peers.retain(|item| !old_peers.contains(item));
         }
 
+        // peers that only changed their endpoint are updated in place, removing them would
+        // drop the peer that was just added
+        old_peers.retain(|peer| !new_peers.iter().any(|item| (item.pubkey == peer.pubkey) && (item.wg_interface == peer.wg_interface)));
+
         // Remove old peers from wireguard interfaces
-        for peer in old_peers {
-            if let Some(endpoint) = peer.endpoint {
-                match remove_peer(peer) {
-                    Ok(_) => info!("Removed peer {:?} @ {}", endpoint, wg.name),
-                    Err(error) => error!("Error removing peer {:?} @ {}: {:?}", endpoint, wg.name, error),
-                }
-            } else {
-                match remove_peer(peer.clone()) { // why the clone?
-                    Ok(_) => info!("Removed peer {:?} @ {}", &peer.pubkey, wg.name),
-                    Err(error) => error!("Error removing peer {:?} @ {}: {:?}", &peer.pubkey, wg.name, error),
-                }
-            }
+        for peer in &old_peers {
+            self.withdraw_peer(peer).await;
         }
     }
 
-    pub async fn ifup(&mut self, net: IpNet) {
+    /// Add or update the interface of a network, returns true if peering queries are needed
+    pub async fn ifup(&mut self, net: IpNet) -> bool {
         info!("Interface up event: {:?}", net);
-        let interface = net.interface().unwrap();
-        let netif: NetworkInterface;
-
-        // Get next hop
-        let (default, gw) = next_hop(net).await;        
-        debug!("Next hop for network {:?} is {:?}", net, gw);
-        netif = NetworkInterface {
-            name: interface.name.clone(),
+        self.update_network(net).await
+    }
+
+    /// Recompute the next hop, default flag and metric of all networks, the default flag compares
+    /// the networks of all interfaces. Returns true if peering queries are needed.
+    pub async fn update_routes(&mut self) -> bool {
+        let nets: Vec<IpNet> = self.interfaces.iter().filter_map(|item| item.net).collect();
+
+        let mut changed = false;
+        for net in nets {
+            changed |= self.update_network(net).await;
+        }
+        if changed {
+            info!("Next hops changed with the routing table");
+        }
+        changed
+    }
+
+    /// Mark of the packets of the managed wireguard interfaces, policy routing may depend on it
+    fn fwmark(&self) -> u32 {
+        self.interfaces
+            .iter()
+            .filter(|item| self.is_managed(item))
+            .find_map(|item| item.wireguard.as_ref().map(|wg| wg.fwmark))
+            .unwrap_or_default()
+    }
+
+    /// Add or update the interface of a network from the routing table
+    async fn update_network(&mut self, net: IpNet) -> bool {
+        // the address may be gone again before a debounced change is handled
+        let Some(interface_name) = net.interface_name() else {
+            debug!("Network {:?} disappeared before it could be loaded", net);
+            return false;
+        };
+        let name = interface_name.clone();
+        let wireguard = blocking(&self.backend, move |backend| query_wg_info(backend, &name)).await;
+
+        // Get next hop, encrypted packets carry the mark of the wireguard interfaces
+        let hop = next_hop(net, self.fwmark()).await;
+        debug!("Next hop for network {:?} is {:?}", net, hop);
+        let netif = NetworkInterface {
+            name: interface_name.clone(),
             net: Some(net),
-            nexthop: gw,
-            is_default: default,
+            nexthop: hop.gateway,
+            is_default: hop.default,
+            metric: hop.metric,
+            fingerprint: None,
             peers: vec![],
-            wireguard: query_wg_info(&interface.name)
+            wireguard,
         };
 
         // add interface to state
-        let changed = self.add_or_update_interface(netif.clone());
-        if changed {
-            self.perform_queries().await;
-        }
+        self.add_or_update_interface(netif)
     }
     
     pub async fn ifdown(&mut self, net: IpNet) {
@@ -140,22 +442,307 @@ impl StateManager {
         let result: Vec<Peer> = self.interfaces
             .clone()
             .into_iter()
-            .filter(|item| (item.net == Some(net)))
+            .filter(|item| item.net == Some(net))
             .flat_map(|item| item.peers)
             .collect();
 
-        self.interfaces.retain(|item| (item.net != Some(net)));
+        self.interfaces.retain(|item| item.net != Some(net));
         
         // Remove peers from wireguard
-        for peer in result {
-            match remove_peer(peer.clone()) { // why the clone?
-                Ok(_) => info!("Removed peer {:?} @ {}", &peer.pubkey, &peer.wg_interface.unwrap()),
-                Err(error) => error!("Error removing peer {:?} @ {}: {:?}", &peer.pubkey, &peer.wg_interface.unwrap(), error),
+        for peer in &result {
+            self.withdraw_peer(peer).await;
+        }
+    }
+
+    /// Clean up peers a previous run left on the wireguard interfaces, they are adopted or removed
+    /// depending on the settings. Peers that are managed already are not touched.
+    pub async fn reconcile(&mut self) {
+        let leftovers: Vec<Peer> = self.journal.entries()
+            .iter()
+            .map(Peer::from)
+            .filter(|peer| !self.interfaces.iter().flat_map(|item| &item.peers).any(|item| (item.pubkey == peer.pubkey) && (item.wg_interface == peer.wg_interface)))
+            .collect();
+
+        for peer in leftovers {
+            let wg_interface = peer.wg_interface.clone().unwrap_or_default();
+
+            // peers that are not on the device anymore need no cleanup, the entry is kept when the
+            // device can not be read for now
+            let name = wg_interface.clone();
+            let gone = match blocking(&self.backend, move |backend| list_peers(backend, &name)).await {
+                Ok(peers) => !peers.contains(&peer.pubkey),
+                Err(WireguardError::NoSuchDevice(_)) => {
+                    debug!("Interface {} of left over peer {} is gone", wg_interface, peer.pubkey);
+                    self.journal.forget(&peer);
+                    continue;
+                }
+                Err(error) => {
+                    warn!("Keeping left over peer {} of interface {}: {}", peer.pubkey, wg_interface, error);
+                    continue;
+                }
+            };
+            if gone {
+                debug!("Left over peer {} is gone from interface {}", peer.pubkey, wg_interface);
+                // a static peer that was lost while it was restored is added again, the entry is
+                // its only copy
+                if self.journal.find(&wg_interface, &peer.pubkey).is_some_and(|entry| entry.original.is_some()) {
+                    self.withdraw_peer(&peer).await;
+                } else {
+                    self.journal.forget(&peer);
+                }
+                continue;
+            }
+
+            // adopt peers that are still reachable, the next query removes them if they are outdated
+            if self.settings.reconcile == ReconcileMode::Adopt {
+                // a link local endpoint stays on the interface of its scope
+                let interface = self.interfaces.iter_mut().find(|item| match (item.net, peer.endpoint) {
+                    (Some(net), Some(endpoint)) if is_link_local(&endpoint) => {
+                        net.contains(&endpoint) && peer.scope_id.is_some() && (net.interface().map(|netif| netif.index) == peer.scope_id)
+                    }
+                    (Some(net), Some(endpoint)) => net.contains(&endpoint),
+                    _ => false,
+                });
+                if let Some(interface) = interface {
+                    info!("Adopting left over peer {} @ {:?} on interface {}", peer.pubkey, peer.endpoint, wg_interface);
+                    interface.peers.push(peer);
+                    continue;
+                }
             }
+
+            info!("Removing left over peer {} @ {:?} from interface {}", peer.pubkey, peer.endpoint, wg_interface);
+            self.withdraw_peer(&peer).await;
+        }
+    }
+
+    /// Replace the networks with the ones currently configured without querying, networks that
+    /// went away while suspended are removed. Left over peers are cleaned up and queries are enabled.
+    pub async fn load_networks(&mut self, nets: Vec<IpNet>) {
+        let gone: Vec<IpNet> = self.interfaces.iter().filter_map(|item| item.net).filter(|net| !nets.contains(net)).collect();
+        for net in gone {
+            self.ifdown(net).await;
+        }
+        // wireguard interfaces first, their mark decides the next hops of the underlay networks
+        let names: Vec<Option<String>> = nets.iter().map(|net| net.interface_name()).collect();
+        let is_wireguard = blocking(&self.backend, move |backend| {
+            names.iter().map(|name| name.as_ref().is_some_and(|name| query_wg_info(backend, name).is_some())).collect::<Vec<bool>>()
+        })
+        .await;
+        let mut nets: Vec<(IpNet, bool)> = nets.into_iter().zip(is_wireguard).collect();
+        nets.sort_by_key(|(_, is_wireguard)| !is_wireguard);
+        for (net, _) in nets {
+            self.ifup(net).await;
         }
+        self.reconcile().await;
+        self.suspended = false;
+    }
+
+    /// Load the current networks, then run one query on all interfaces.
+    /// Returns the number of performed and failed queries.
+    pub async fn load_interfaces(&mut self, nets: Vec<IpNet>) -> (usize, usize) {
+        self.load_networks(nets).await;
+        self.perform_queries().await
     }
     
-    pub async fn refresh(&mut self) {
-        self.perform_queries().await;
+    /// Check the handshakes of all managed peers, peers that could not be reached directly are
+    /// withdrawn so traffic falls back to the VPN server. Returns the reports of the outcomes for the servers.
+    pub async fn check_health(&mut self) -> Option<ReportRound> {
+        if self.dry_run {
+            return None;
+        }
+
+        let now = unix_time();
+        let timeout: u64 = self.settings.handshake_timeout.into();
+        let managed: Vec<Peer> = self.interfaces.iter().flat_map(|item| item.peers.clone()).collect();
+
+        // forget peers that are not managed anymore, failed ones are kept to not add them again
+        self.health.retain(|record| (record.health == PeerHealth::Failed) || managed.iter().any(|peer| record.matches(peer)));
+
+        let mut failed: Vec<Peer> = vec![];
+        let mut outcomes: Vec<HealthRecord> = vec![];
+        for peer in &managed {
+            let wg_interface = peer.wg_interface.clone().unwrap_or_default();
+            let (name, pubkey) = (wg_interface.clone(), peer.pubkey.clone());
+            let stats = match blocking(&self.backend, move |backend| peer_stats(backend, &name, &pubkey)).await {
+                Some(stats) => stats,
+                None => continue,
+            };
+
+            let record = match self.health.iter().position(|record| record.matches(peer)) {
+                Some(index) => &mut self.health[index],
+                None => {
+                    // adopted peers are watched from now on
+                    self.health.push(HealthRecord::new(wg_interface, peer.pubkey.clone(), peer.endpoint, now, &stats));
+                    self.health.last_mut().unwrap()
+                }
+            };
+            let before = record.health;
+            record.update(&stats, now, timeout);
+
+            if (before != PeerHealth::Connected) && (record.health == PeerHealth::Connected) {
+                info!("Direct connection to peer {} @ {:?} established", peer.pubkey, peer.endpoint);
+            }
+            if before != record.health {
+                outcomes.push(record.clone());
+            }
+            if record.health == PeerHealth::Failed {
+                failed.push(peer.clone());
+            }
+        }
+
+        for peer in &failed {
+            warn!("No handshake with peer {} @ {:?} within {} seconds, falling back to the VPN server", peer.pubkey, peer.endpoint, timeout);
+            for interface in &mut self.interfaces {
+                interface.peers.retain(|item| item != peer);
+            }
+            self.withdraw_peer(peer).await;
+        }
+
+        self.prepare_reports(outcomes)
+    }
+
+    /// Check the health of the managed peers, the reports are sent in a background task
+    pub async fn spawn_health_check(&mut self) {
+        if let Some(round) = self.check_health().await {
+            tokio::spawn(round.send());
+        }
+    }
+
+    /// Reports of direct connection outcomes for the servers of the wireguard interfaces
+    fn prepare_reports(&self, outcomes: Vec<HealthRecord>) -> Option<ReportRound> {
+        let timeout: u64 = self.settings.handshake_timeout.into();
+
+        let mut reports = vec![];
+        for wg in self.interfaces.iter().filter(|item| self.is_managed(item)) {
+            // interfaces with multiple addresses only report once
+            if reports.iter().any(|(item, _, _): &(NetworkInterface, _, _)| item.name == wg.name) {
+                continue;
+            }
+
+            let pubkey = wg.wireguard.clone().unwrap().pubkey.unwrap();
+            let batch: Vec<PeeringReport> = outcomes
+                .iter()
+                .filter(|record| record.interface == wg.name)
+                .map(|record| PeeringReport::new(pubkey.clone(), record, timeout))
+                .collect();
+            if batch.is_empty() {
+                continue;
+            }
+            // a server that does not answer queries does not get reports either
+            if self.breakers.get(&wg.name).is_some_and(|breaker| breaker.state == CircuitState::Open) {
+                debug!("Not sending peering report on interface {}, circuit is open", wg.name);
+                continue;
+            }
+            reports.push((wg.clone(), self.connections.get(&wg.name).cloned(), batch));
+        }
+
+        if reports.is_empty() {
+            return None;
+        }
+        Some(ReportRound { context: self.context(), reports })
+    }
+
+    /// Withdraw all managed peers so traffic is routed through the VPN server again,
+    /// unless the settings say to leave them for the next start
+    pub async fn shutdown(&mut self) {
+        let peers: Vec<Peer> = self.interfaces.iter_mut().flat_map(|item| std::mem::take(&mut item.peers)).collect();
+
+        if self.settings.leave_peers {
+            info!("Leaving {} peers on the wireguard interfaces", peers.len());
+            return;
+        }
+
+        info!("Removing {} peers from the wireguard interfaces", peers.len());
+        for peer in &peers {
+            self.withdraw_peer(peer).await;
+        }
+    }
+
+    /// Re-run peering queries, returns the number of performed and failed queries
+    pub async fn refresh(&mut self) -> (usize, usize) {
+        self.perform_queries().await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::state::journal::Journal;
+    use crate::state::structs::Peer;
+    use crate::state::testing::{devices, peer, state};
+    use crate::wireguard::backend::{PeerConfig, WireguardBackend};
+    use crate::wireguard::error::WireguardError;
+
+    const KEY: &str = "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=";
+
+    #[tokio::test]
+    async fn unreadable_devices_keep_left_over_peers() {
+        let mut state = state("reconcile-test.json");
+        let backend = devices(&mut state);
+        state.journal.record(&peer(KEY, "192.168.1.20"), None);
+
+        *backend.read_error.lock().unwrap() = Some(WireguardError::PermissionDenied("wg0".into()));
+        state.reconcile().await;
+        assert_eq!(state.journal.entries().len(), 1);
+
+        // a device that does not exist has no peers to clean up
+        *backend.read_error.lock().unwrap() = None;
+        backend.devices.lock().unwrap().clear();
+        state.reconcile().await;
+        assert!(state.journal.entries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn static_peers_are_restored() {
+        let mut state = state("restore-test.json");
+        let backend = devices(&mut state);
+        let config = PeerConfig {
+            public_key: [2; 32],
+            preshared_key: Some([7; 32]),
+            persistent_keepalive: Some(25),
+            allowed_ips: vec![("10.0.0.0".parse().unwrap(), 24)],
+            ..Default::default()
+        };
+        backend.set_peer("wg0", &config).unwrap();
+
+        let peer = Peer { ip: Some(vec!["10.0.0.3".parse().unwrap()]), ..peer(KEY, "192.168.1.20") };
+        assert!(state.install_peer(&peer).await);
+        // the pre-shared key survives a restart
+        let journal = Journal::load(state.settings.state_file_path());
+        assert_eq!(journal.entries()[0].original.as_ref().unwrap().preshared_key, Some("BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=".into()));
+
+        state.withdraw_peer(&peer).await;
+        let peers = backend.peers("wg0").unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].preshared_key, [7; 32]);
+        assert_eq!(peers[0].persistent_keepalive_interval, 25);
+        assert_eq!(peers[0].allowed_ips.len(), 1);
+        assert_eq!(peers[0].allowed_ips[0].cidr_mask, 24);
+        assert!(state.journal.entries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lost_static_peers_are_added_again() {
+        let mut state = state("restore-lost-test.json");
+        let backend = devices(&mut state);
+        let config = PeerConfig { public_key: [2; 32], allowed_ips: vec![("10.0.0.0".parse().unwrap(), 24)], ..Default::default() };
+        backend.set_peer("wg0", &config).unwrap();
+        let peer = Peer { ip: Some(vec!["10.0.0.3".parse().unwrap()]), ..peer(KEY, "192.168.1.20") };
+        assert!(state.install_peer(&peer).await);
+
+        // the peer is gone from the device and the device refuses changes
+        backend.remove_peer("wg0", &[2; 32]).unwrap();
+        *backend.error.lock().unwrap() = Some(WireguardError::Rejected("wg0".into(), 22));
+        state.withdraw_peer(&peer).await;
+        state.reconcile().await;
+        assert_eq!(state.journal.entries().len(), 1);
+        assert!(backend.peers("wg0").unwrap().is_empty());
+
+        *backend.error.lock().unwrap() = None;
+        state.reconcile().await;
+        assert!(state.journal.entries().is_empty());
+        let peers = backend.peers("wg0").unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].allowed_ips[0].cidr_mask, 24);
     }
 }
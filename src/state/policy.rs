@@ -0,0 +1,257 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use if_watch::IpNet;
+
+use crate::wireguard::information::decode_key;
+
+use super::filter::InterfaceFilter;
+use super::structs::{peer_network, NetworkInterface, Peer};
+
+/// Peer that is configured on a wireguard interface without autopeering
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaticPeer {
+    pub pubkey: String,
+    pub allowed_ips: Vec<IpNet>,
+}
+
+/// Why a peer offered by the server is not installed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rejection {
+    MalformedKey,
+    /// The public key of the wireguard interface itself
+    OwnKey,
+    /// Allowed ip that is no host inside the tunnel network
+    OutsideTunnel(IpAddr),
+    /// Allowed ip of this host or of the server
+    ReservedAddress(IpAddr),
+    /// Key of a static peer that routes more than single hosts, like the server
+    StaticKey,
+    /// Allowed ip that a static peer routes, with the key of that peer
+    StaticAllowedIp(IpAddr, String),
+    /// Endpoint that is not inside a local underlay network
+    OutsideUnderlay(Option<IpAddr>),
+    /// Allowed ip that another offered or managed peer has as well, with the key of that peer
+    DuplicateAllowedIp(IpAddr, String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::MalformedKey => write!(f, "malformed public key"),
+            Rejection::OwnKey => write!(f, "public key of this interface"),
+            Rejection::OutsideTunnel(ip) => write!(f, "allowed ip {} is no host in the tunnel network", ip),
+            Rejection::ReservedAddress(ip) => write!(f, "allowed ip {} is the address of this host or the server", ip),
+            Rejection::StaticKey => write!(f, "public key of a statically configured peer"),
+            Rejection::StaticAllowedIp(ip, pubkey) => write!(f, "allowed ip {} belongs to statically configured peer {}", ip, pubkey),
+            Rejection::OutsideUnderlay(Some(endpoint)) => write!(f, "endpoint {} is not in a local network", endpoint),
+            Rejection::OutsideUnderlay(None) => write!(f, "no endpoint"),
+            Rejection::DuplicateAllowedIp(ip, pubkey) => write!(f, "allowed ip {} is claimed by peer {} as well", ip, pubkey),
+        }
+    }
+}
+
+/// Checks the peers the server offered for one wireguard interface before they touch the device
+#[derive(Debug)]
+pub struct PeerPolicy<'a> {
+    pub wg: &'a NetworkInterface,
+    pub interfaces: &'a [NetworkInterface],
+    /// Underlay interfaces endpoints may be in
+    pub underlay: &'a InterfaceFilter,
+    pub static_peers: Vec<StaticPeer>,
+    /// Peers autopeering installed on the wireguard interface
+    pub managed: Vec<Peer>,
+    /// Address of the server
+    pub server: Option<IpAddr>,
+}
+
+impl PeerPolicy<'_> {
+    pub fn check(&self, peer: &Peer) -> Result<(), Rejection> {
+        if decode_key(&peer.pubkey).is_err() {
+            return Err(Rejection::MalformedKey);
+        }
+        if self.wg.wireguard.as_ref().and_then(|wg| wg.pubkey.as_ref()) == Some(&peer.pubkey) {
+            return Err(Rejection::OwnKey);
+        }
+
+        // a query runs for one address of the interface, a dual stack tunnel has more networks
+        let tunnels: Vec<IpNet> = self.interfaces.iter().filter(|item| item.name == self.wg.name).filter_map(|item| item.net).collect();
+        let in_tunnel = |ip: &IpAddr| tunnels.iter().any(|tunnel| is_tunnel_host(tunnel, ip));
+
+        // a static peer that only routes hosts inside the tunnel is adopted, others would lose their routes
+        let hosts_only = |item: &StaticPeer| item.allowed_ips.iter().all(|net| is_host(net) && in_tunnel(&net.addr()));
+        if self.static_peers.iter().any(|item| (item.pubkey == peer.pubkey) && !hosts_only(item)) {
+            return Err(Rejection::StaticKey);
+        }
+
+        for ip in peer.ip.iter().flatten() {
+            if !in_tunnel(ip) {
+                return Err(Rejection::OutsideTunnel(*ip));
+            }
+            if tunnels.iter().any(|tunnel| tunnel.addr() == *ip) || (Some(*ip) == self.server) {
+                return Err(Rejection::ReservedAddress(*ip));
+            }
+            let owner = self.static_peers
+                .iter()
+                .filter(|item| item.pubkey != peer.pubkey)
+                .find(|item| item.allowed_ips.iter().any(|net| is_host(net) && (net.addr() == *ip)));
+            if let Some(owner) = owner {
+                return Err(Rejection::StaticAllowedIp(*ip, owner.pubkey.clone()));
+            }
+        }
+
+        match peer_network(self.interfaces, self.underlay, peer) {
+            Some(_) => Ok(()),
+            None => Err(Rejection::OutsideUnderlay(peer.endpoint)),
+        }
+    }
+
+    /// Allowed ips of `peer` that no other peer in `peers` or the managed peers has, an allowed ip
+    /// routes to one peer only. A managed peer gives up its addresses when it is withdrawn.
+    fn check_unique(&self, peer: &Peer, peers: &[Peer]) -> Result<(), Rejection> {
+        for ip in peer.ip.iter().flatten() {
+            let owner = peers
+                .iter()
+                .chain(&self.managed)
+                .find(|item| (item.pubkey != peer.pubkey) && item.ip.iter().flatten().any(|item| item == ip));
+            if let Some(owner) = owner {
+                return Err(Rejection::DuplicateAllowedIp(*ip, owner.pubkey.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Peers that pass the checks, every rejected peer is logged with the reason. Peers that claim
+    /// the same allowed ip are all rejected, the server can not pick one of them.
+    pub fn sanitise(&self, peers: Vec<Peer>) -> Vec<Peer> {
+        let reject = |peer: &Peer, rejection: Rejection| {
+            warn!("Rejecting peer {} @ {:?} offered on interface {}: {}", peer.pubkey, peer.endpoint, self.wg.name, rejection);
+            false
+        };
+        let peers: Vec<Peer> = peers
+            .into_iter()
+            .filter(|peer| self.check(peer).map_or_else(|rejection| reject(peer, rejection), |_| true))
+            .collect();
+        peers
+            .iter()
+            .filter(|peer| self.check_unique(peer, &peers).map_or_else(|rejection| reject(peer, rejection), |_| true))
+            .cloned()
+            .collect()
+    }
+}
+
+fn is_host(net: &IpNet) -> bool {
+    net.prefix_len() == net.max_prefix_len()
+}
+
+/// Address of a host inside the tunnel network, the network and broadcast addresses are none
+fn is_tunnel_host(tunnel: &IpNet, ip: &IpAddr) -> bool {
+    if !tunnel.contains(ip) {
+        return false;
+    }
+    if tunnel.prefix_len() + 1 >= tunnel.max_prefix_len() {
+        return true;
+    }
+    match tunnel {
+        IpNet::V4(_) => (*ip != tunnel.network()) && (*ip != tunnel.broadcast()),
+        IpNet::V6(_) => *ip != tunnel.network(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::state::filter::InterfaceFilter;
+    use crate::state::policy::{PeerPolicy, Rejection, StaticPeer};
+    use crate::state::structs::Peer;
+    use crate::state::testing::{self, interface, wireguard};
+
+    fn peer(pubkey: &str, endpoint: &str, ip: &str) -> Peer {
+        Peer { ip: Some(vec![ip.parse().unwrap()]), ..testing::peer(pubkey, endpoint) }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    const OWN: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+    const OTHER: &str = "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=";
+    const SERVER: &str = "AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=";
+    const ADOPTED: &str = "BAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQ=";
+
+    #[test]
+    fn rejected_peers() {
+        let wg = wireguard("wg0", "10.0.0.2/24", OWN);
+        let mut wg6 = wg.clone();
+        wg6.net = Some("fd00:aa::2/64".parse().unwrap());
+        let interfaces = vec![wg.clone(), wg6, interface("eth0", "192.168.1.10/24"), interface("docker0", "172.17.0.1/16")];
+        let underlay = InterfaceFilter { include: vec![], exclude: vec!["docker*".into()] };
+        let policy = PeerPolicy {
+            wg: &wg,
+            interfaces: &interfaces,
+            underlay: &underlay,
+            static_peers: vec![
+                StaticPeer { pubkey: SERVER.into(), allowed_ips: vec!["10.0.0.0/24".parse().unwrap()] },
+                StaticPeer { pubkey: ADOPTED.into(), allowed_ips: vec!["10.0.0.7/32".parse().unwrap()] },
+            ],
+            managed: vec![],
+            server: Some(ip("10.0.0.1")),
+        };
+
+        assert_eq!(policy.check(&peer(OTHER, "192.168.1.20", "10.0.0.3")), Ok(()));
+        // statically configured peers with a host route are adopted
+        assert_eq!(policy.check(&peer(ADOPTED, "192.168.1.21", "10.0.0.7")), Ok(()));
+
+        assert_eq!(policy.check(&peer("not a key", "192.168.1.20", "10.0.0.3")), Err(Rejection::MalformedKey));
+        assert_eq!(policy.check(&peer(OWN, "192.168.1.20", "10.0.0.3")), Err(Rejection::OwnKey));
+        assert_eq!(policy.check(&peer(SERVER, "192.168.1.20", "10.0.0.3")), Err(Rejection::StaticKey));
+        assert_eq!(policy.check(&peer(OTHER, "192.168.1.20", "0.0.0.0")), Err(Rejection::OutsideTunnel(ip("0.0.0.0"))));
+        assert_eq!(policy.check(&peer(OTHER, "192.168.1.20", "10.0.0.255")), Err(Rejection::OutsideTunnel(ip("10.0.0.255"))));
+        assert_eq!(policy.check(&peer(OTHER, "192.168.1.20", "10.0.0.1")), Err(Rejection::ReservedAddress(ip("10.0.0.1"))));
+        assert_eq!(policy.check(&peer(OTHER, "192.168.1.20", "10.0.0.2")), Err(Rejection::ReservedAddress(ip("10.0.0.2"))));
+        assert_eq!(policy.check(&peer(OTHER, "192.168.1.20", "10.0.0.7")), Err(Rejection::StaticAllowedIp(ip("10.0.0.7"), ADOPTED.into())));
+        assert_eq!(policy.check(&peer(OTHER, "8.8.8.8", "10.0.0.3")), Err(Rejection::OutsideUnderlay(Some(ip("8.8.8.8")))));
+        assert_eq!(policy.check(&peer(OTHER, "172.17.0.5", "10.0.0.3")), Err(Rejection::OutsideUnderlay(Some(ip("172.17.0.5")))));
+        // the tunnel itself is no underlay
+        assert_eq!(policy.check(&peer(OTHER, "10.0.0.9", "10.0.0.3")), Err(Rejection::OutsideUnderlay(Some(ip("10.0.0.9")))));
+
+        // a dual stack peer has an address in both networks of the tunnel
+        let mut dual = peer(OTHER, "192.168.1.20", "10.0.0.3");
+        dual.ip = Some(vec![ip("10.0.0.3"), ip("fd00:aa::3")]);
+        assert_eq!(policy.check(&dual), Ok(()));
+        dual.ip = Some(vec![ip("10.0.0.3"), ip("fd00:bb::3")]);
+        assert_eq!(policy.check(&dual), Err(Rejection::OutsideTunnel(ip("fd00:bb::3"))));
+        dual.ip = Some(vec![ip("10.0.0.3"), ip("fd00:aa::2")]);
+        assert_eq!(policy.check(&dual), Err(Rejection::ReservedAddress(ip("fd00:aa::2"))));
+
+        let peers = vec![peer(OTHER, "192.168.1.20", "10.0.0.3"), peer(OWN, "192.168.1.20", "10.0.0.3")];
+        assert_eq!(policy.sanitise(peers), vec![peer(OTHER, "192.168.1.20", "10.0.0.3")]);
+    }
+
+    #[test]
+    fn duplicate_allowed_ips() {
+        const THIRD: &str = "BQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQU=";
+        let wg = wireguard("wg0", "10.0.0.2/24", OWN);
+        let interfaces = vec![wg.clone(), interface("eth0", "192.168.1.10/24")];
+        let underlay = InterfaceFilter::default();
+        let mut policy = PeerPolicy { wg: &wg, interfaces: &interfaces, underlay: &underlay, static_peers: vec![], managed: vec![], server: None };
+
+        // the same peer over two endpoints is no collision
+        let peers = vec![peer(OTHER, "192.168.1.20", "10.0.0.3"), peer(OTHER, "192.168.1.21", "10.0.0.3"), peer(THIRD, "192.168.1.22", "10.0.0.4")];
+        assert_eq!(policy.sanitise(peers.clone()), peers);
+
+        let peers = vec![peer(OTHER, "192.168.1.20", "10.0.0.3"), peer(THIRD, "192.168.1.22", "10.0.0.3"), peer(ADOPTED, "192.168.1.23", "10.0.0.5")];
+        assert_eq!(policy.sanitise(peers), vec![peer(ADOPTED, "192.168.1.23", "10.0.0.5")]);
+        assert_eq!(
+            policy.check_unique(&peer(THIRD, "192.168.1.22", "10.0.0.3"), &[peer(OTHER, "192.168.1.20", "10.0.0.3")]),
+            Err(Rejection::DuplicateAllowedIp(ip("10.0.0.3"), OTHER.into()))
+        );
+
+        // a managed peer keeps its address until it is withdrawn
+        policy.managed = vec![peer(OTHER, "192.168.1.20", "10.0.0.3")];
+        assert_eq!(policy.sanitise(vec![peer(THIRD, "192.168.1.22", "10.0.0.3")]), vec![]);
+        assert_eq!(policy.sanitise(vec![peer(OTHER, "192.168.1.21", "10.0.0.3")]), vec![peer(OTHER, "192.168.1.21", "10.0.0.3")]);
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use futures::future::join_all;
+use if_watch::IpNet;
+
+use crate::http::client::Connection;
+use crate::http::discovery::discover;
+use crate::http::peering::{peering_report, peering_request, PeeringReport};
+use crate::network::neighbour::{neighbour_mac, network_fingerprint};
+use crate::network::stun::{probe, resolve_servers, PublicAddress};
+use crate::network::utils::GetInterface;
+use crate::wireguard::backend::WireguardBackend;
+
+use super::structs::{is_link_local, NetworkInterface, Peer, Settings};
+
+
+/// Copy of the state a query round needs, so the round can run outside of the event loop
+#[derive(Clone, Debug)]
+pub struct QueryContext {
+    pub settings: Settings,
+    pub interfaces: Vec<NetworkInterface>,
+    pub public_addresses: HashMap<IpAddr, PublicAddress>,
+    pub backend: Arc<dyn WireguardBackend>,
+    /// Wireguard interfaces whose server got a signed request, see `StateManager::signed`
+    pub signed: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Outcome of the peering query of one wireguard interface
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryOutcome {
+    pub interface: NetworkInterface,
+    /// Client used for the query, cached for the next round
+    pub connection: Option<Connection>,
+    pub result: Result<Vec<Peer>, String>,
+}
+
+/// Results of a query round, sent back to the event loop
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryResults {
+    /// Round number, results of a superseded round are discarded
+    pub generation: u64,
+    /// Unix timestamp of the start of the round
+    pub started: u64,
+    pub public_addresses: HashMap<IpAddr, PublicAddress>,
+    /// Gateway fingerprints by interface name and network
+    pub fingerprints: Vec<(String, Option<IpNet>, Option<String>)>,
+    pub outcomes: Vec<QueryOutcome>,
+    /// Interfaces skipped because the circuit of their server is open
+    pub skipped: usize,
+}
+
+/// Peering queries prepared on the event loop, `run` needs no access to the state manager
+#[derive(Clone, Debug)]
+pub struct QueryRound {
+    pub generation: u64,
+    pub started: u64,
+    pub context: QueryContext,
+    /// Wireguard interfaces to query with the cached client of their server
+    pub queries: Vec<(NetworkInterface, Option<Connection>)>,
+    pub skipped: usize,
+}
+
+impl QueryRound {
+    /// Probe the underlay networks, then query all servers at once
+    pub async fn run(mut self) -> QueryResults {
+        if !self.queries.is_empty() {
+            self.context.public_addresses = probe_public_addresses(&self.context).await;
+            update_fingerprints(&mut self.context.interfaces).await;
+        }
+
+        let context = &self.context;
+        let queries = self.queries
+            .iter()
+            .map(|(wg, cached)| query(context, wg, cached.clone()));
+        let outcomes = join_all(queries).await;
+
+        QueryResults {
+            generation: self.generation,
+            started: self.started,
+            fingerprints: self.context.interfaces
+                .iter()
+                .filter(|item| item.is_underlay())
+                .map(|item| (item.name.clone(), item.net, item.fingerprint.clone()))
+                .collect(),
+            public_addresses: self.context.public_addresses,
+            outcomes,
+            skipped: self.skipped,
+        }
+    }
+}
+
+/// Peering query of one wireguard interface
+async fn query(context: &QueryContext, wg: &NetworkInterface, cached: Option<Connection>) -> QueryOutcome {
+    info!("Performing peering query on interface {} for {}...", wg.name, wg.net.unwrap());
+    let connection = match connection(&context.settings, wg, cached).await {
+        Ok(connection) => connection,
+        Err(error) => return QueryOutcome { interface: wg.clone(), connection: None, result: Err(error) },
+    };
+
+    let result = peering_request(context, wg, &connection).await.map(|response| response.peers);
+    QueryOutcome { interface: wg.clone(), connection: Some(connection), result }
+}
+
+/// Peering reports prepared on the event loop, sent in the background like a query round
+#[derive(Debug)]
+pub struct ReportRound {
+    pub context: QueryContext,
+    /// Wireguard interfaces with the cached client of their server and their reports
+    pub reports: Vec<(NetworkInterface, Option<Connection>, Vec<PeeringReport>)>,
+}
+
+impl ReportRound {
+    /// Send the reports to all servers at once
+    pub async fn send(self) {
+        let sends = self.reports
+            .iter()
+            .map(|(wg, cached, reports)| self.report(wg, cached.clone(), reports));
+        join_all(sends).await;
+    }
+
+    async fn report(&self, wg: &NetworkInterface, cached: Option<Connection>, reports: &[PeeringReport]) {
+        // a server that was not queried yet is discovered for this report only
+        let connection = match cached {
+            Some(connection) => Ok(connection),
+            None => connection(&self.context.settings, wg, None).await,
+        };
+        let result = match connection {
+            Ok(connection) => peering_report(&self.context, wg, &connection, reports).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            error!("Could not send peering report on interface {}: {}", wg.name, error);
+        }
+    }
+}
+
+/// Client for the server of a wireguard interface, the server is discovered again on every call
+/// but the cached client is reused while the server stays the same
+pub async fn connection(settings: &Settings, wg: &NetworkInterface, cached: Option<Connection>) -> Result<Connection, String> {
+    let server = settings.server(&wg.name);
+    let endpoint = discover(&server, wg).await?;
+    debug!("Server of interface {} is {}", wg.name, endpoint);
+
+    if let Some(connection) = cached {
+        if connection.matches(&server, &endpoint, &settings.http) {
+            return Ok(connection);
+        }
+    }
+
+    Connection::new(server, endpoint, &settings.http)
+        .map_err(|error| format!("Invalid server settings for interface {}: {}", wg.name, error))
+}
+
+/// Find the external address of every underlay network with the configured STUN servers
+async fn probe_public_addresses(context: &QueryContext) -> HashMap<IpAddr, PublicAddress> {
+    let mut public_addresses = HashMap::new();
+    if context.settings.stun_servers.is_empty() {
+        return public_addresses;
+    }
+
+    let stun_servers = &context.settings.stun_servers;
+    // the probe has to take the path of the encrypted packets, not the tunnel
+    let fwmark = context.interfaces
+        .iter()
+        .filter_map(|item| item.wireguard.as_ref().map(|wg| wg.fwmark))
+        .find(|fwmark| *fwmark != 0)
+        .unwrap_or_default();
+    let probes = context.interfaces
+        .iter()
+        .filter(|item| item.is_underlay() && context.settings.underlay_interfaces.matches(&item.name))
+        .filter_map(|item| item.net.map(|net| (item.name.as_str(), net.addr())))
+        // a link local address without its scope can not be bound, STUN servers are not on the link anyway
+        .filter(|(_, local)| !is_link_local(local))
+        .map(|(device, local)| async move {
+            let servers = resolve_servers(stun_servers, local).await;
+            (local, probe(local, device, fwmark, &servers).await)
+        });
+
+    for (local, result) in join_all(probes).await {
+        match result {
+            Ok(public) => {
+                debug!("External address of {} is {} ({:?})", local, public.address, public.mapping);
+                public_addresses.insert(local, public);
+            }
+            Err(error) => debug!("No external address for {}: {}", local, error),
+        }
+    }
+    public_addresses
+}
+
+/// Fingerprint the underlay networks by the MAC address of their gateway, the neighbour table
+/// may not know the gateway right after the interface came up so this is repeated every round
+async fn update_fingerprints(interfaces: &mut [NetworkInterface]) {
+    for interface in interfaces.iter_mut() {
+        let (Some(gateway), Some(net)) = (interface.nexthop, interface.net) else {
+            continue;
+        };
+        if !interface.is_underlay() {
+            continue;
+        }
+
+        let fingerprint = match net.interface() {
+            Some(netif) => neighbour_mac(gateway, netif.index).await.map(|mac| network_fingerprint(&mac)),
+            None => None,
+        };
+        if fingerprint.is_none() {
+            debug!("No MAC address of gateway {} in the neighbour table", gateway);
+        }
+        interface.fingerprint = fingerprint;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::state::structs::Peer;
+    use crate::state::testing::{answer, devices, peer, state};
+    use crate::wireguard::backend::WireguardBackend;
+    use crate::wireguard::error::WireguardError;
+
+    const KEY: &str = "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=";
+
+    /// Peer on the local network with an address in the tunnel
+    fn direct(pubkey: &str) -> Peer {
+        Peer { ip: Some(vec!["10.0.0.3".parse().unwrap()]), ..peer(pubkey, "192.168.1.20") }
+    }
+
+    #[tokio::test]
+    async fn superseded_round_is_discarded() {
+        let mut state = state("query-test-superseded.json");
+        let first = state.prepare_queries().unwrap();
+        let second = state.prepare_queries().unwrap();
+        assert!(second.generation > first.generation);
+
+        assert_eq!(state.apply_queries(first.run().await).await, (0, 0));
+        // the open circuit skips the query, it counts as failed
+        assert_eq!(state.apply_queries(second.run().await).await, (1, 1));
+
+        state.suspended = true;
+        assert!(state.prepare_queries().is_none());
+    }
+
+    #[tokio::test]
+    async fn peers_are_installed_on_the_device() {
+        let mut state = state("query-test-installed.json");
+        let backend = devices(&mut state);
+
+        let round = state.prepare_queries().unwrap();
+        let results = answer(&state, &round, vec![direct(KEY)]);
+        assert_eq!(state.apply_queries(results).await, (1, 0));
+
+        let peers = backend.peers("wg0").unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].public_key, [2u8; 32]);
+        assert_eq!(peers[0].endpoint, Some("192.168.1.20:51820".parse().unwrap()));
+        assert_eq!(peers[0].allowed_ips[0].cidr_mask, 32);
+        assert_eq!(state.interfaces[1].peers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_peers_are_not_recorded() {
+        let mut state = state("query-test-failed.json");
+        let backend = devices(&mut state);
+        *backend.error.lock().unwrap() = Some(WireguardError::PermissionDenied("wg0".into()));
+
+        let round = state.prepare_queries().unwrap();
+        let results = answer(&state, &round, vec![direct(KEY), direct("not a key")]);
+        assert_eq!(state.apply_queries(results).await, (1, 0));
+        assert!(state.interfaces[1].peers.is_empty());
+
+        // the next round installs the peer once the device accepts it
+        *backend.error.lock().unwrap() = None;
+        let round = state.prepare_queries().unwrap();
+        let results = answer(&state, &round, vec![direct(KEY), direct("not a key")]);
+        state.apply_queries(results).await;
+        assert_eq!(state.interfaces[1].peers.len(), 1);
+        assert_eq!(backend.peers("wg0").unwrap().len(), 1);
+    }
+}
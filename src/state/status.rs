@@ -0,0 +1,118 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::http::client::CircuitBreaker;
+use crate::http::discovery::ServerEndpoint;
+use crate::network::stun::PublicAddress;
+
+use super::health::{HealthRecord, PeerHealth};
+use super::structs::{Peer, StateManager};
+
+/// Status of one network interface
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InterfaceStatus {
+    pub name: String,
+    pub net: Option<String>,
+    pub nexthop: Option<IpAddr>,
+    pub is_default: bool,
+    #[serde(default)]
+    pub metric: Option<u32>,
+    /// External address of the network as seen by a STUN server
+    #[serde(default)]
+    pub public_address: Option<PublicAddress>,
+    pub wireguard_pubkey: Option<String>,
+    pub wireguard_port: Option<u16>,
+    /// Server the peering queries of a wireguard interface are sent to
+    #[serde(default)]
+    pub server: Option<ServerEndpoint>,
+    #[serde(default)]
+    pub circuit: Option<CircuitBreaker>,
+    pub peers: Vec<Peer>,
+}
+
+/// Snapshot of the state manager for status output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    pub suspended: bool,
+    pub dry_run: bool,
+    pub interfaces: Vec<InterfaceStatus>,
+    pub health: Vec<HealthRecord>,
+}
+
+impl From<&StateManager> for Status {
+    fn from(state: &StateManager) -> Self {
+        Self {
+            suspended: state.suspended,
+            dry_run: state.dry_run,
+            interfaces: state.interfaces.iter().map(|item| InterfaceStatus {
+                name: item.name.clone(),
+                net: item.net.map(|net| net.to_string()),
+                nexthop: item.nexthop,
+                is_default: item.is_default,
+                metric: item.metric,
+                public_address: item.net.and_then(|net| state.public_addresses.get(&net.addr()).copied()),
+                wireguard_pubkey: item.wireguard.as_ref().and_then(|wg| wg.pubkey.clone()),
+                wireguard_port: item.wireguard.as_ref().map(|wg| wg.port),
+                server: state.connections.get(&item.name).filter(|_| state.is_managed(item)).map(|connection| connection.endpoint.clone()),
+                circuit: state.breakers.get(&item.name).filter(|_| state.is_managed(item)).cloned(),
+                peers: item.peers.clone(),
+            }).collect(),
+            health: state.health.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "State: {}{}", if self.suspended { "suspended" } else { "active" }, if self.dry_run { " (dry run)" } else { "" })?;
+
+        for interface in &self.interfaces {
+            write!(f, "{}: {}", interface.name, interface.net.as_deref().unwrap_or("-"))?;
+            if let Some(nexthop) = interface.nexthop {
+                write!(f, " via {}", nexthop)?;
+            }
+            if interface.is_default {
+                write!(f, " (default)")?;
+            }
+            if let Some(metric) = interface.metric {
+                write!(f, " metric {}", metric)?;
+            }
+            if let Some(public) = interface.public_address {
+                write!(f, " public {} ({:?})", public.address, public.mapping)?;
+            }
+            if let Some(port) = interface.wireguard_port {
+                write!(f, " wireguard port {} pubkey {}", port, interface.wireguard_pubkey.as_deref().unwrap_or("-"))?;
+            }
+            if let Some(server) = &interface.server {
+                write!(f, " server {}", server)?;
+            }
+            if let Some(circuit) = interface.circuit.as_ref().filter(|circuit| circuit.failures > 0) {
+                write!(f, " ({})", circuit)?;
+            }
+            writeln!(f)?;
+
+            for peer in &interface.peers {
+                write!(f, "    peer {} on {}", peer.pubkey, peer.wg_interface.as_deref().unwrap_or("-"))?;
+                if let Ok(endpoint) = SocketAddr::try_from(peer.clone()) {
+                    write!(f, " endpoint {}", endpoint)?;
+                }
+                if let Some(ips) = &peer.ip {
+                    let ips: Vec<String> = ips.iter().map(|ip| ip.to_string()).collect();
+                    write!(f, " allowed ips {}", ips.join(", "))?;
+                }
+                if let Some(record) = self.health.iter().find(|record| record.matches(peer)) {
+                    write!(f, " ({}, rx {} bytes, tx {} bytes)", record.health, record.rx_bytes, record.tx_bytes)?;
+                }
+                writeln!(f)?;
+            }
+        }
+
+        for record in self.health.iter().filter(|record| record.health == PeerHealth::Failed) {
+            writeln!(f, "Direct connection failed: peer {} on {} endpoint {:?}", record.pubkey, record.interface, record.endpoint)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,7 +1,18 @@
-use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use if_watch::IpNet;
 use serde::{Deserialize, Serialize};
 
+use crate::http::client::{CircuitBreaker, Connection};
+use crate::network::stun::PublicAddress;
+use crate::wireguard::backend::WireguardBackend;
+
+use super::filter::InterfaceFilter;
+use super::health::HealthRecord;
+use super::journal::Journal;
+
 /// Timeout in seconds.
 #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
 pub struct Timeout(u64);
@@ -16,6 +27,34 @@ impl From<Timeout> for u64 {
     }
 }
 
+/// Debounce window in milliseconds.
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+pub struct Debounce(u64);
+impl Default for Debounce {
+    fn default() -> Self {
+        Debounce(1000)
+    }
+}
+impl From<Debounce> for u64 {
+    fn from(value: Debounce) -> Self {
+        value.0
+    }
+}
+
+/// Unix file permission bits.
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+pub struct FileMode(u32);
+impl Default for FileMode {
+    fn default() -> Self {
+        FileMode(0o660)
+    }
+}
+impl From<FileMode> for u32 {
+    fn from(value: FileMode) -> Self {
+        value.0
+    }
+}
+
 /// Peer information
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Peer {
@@ -24,12 +63,42 @@ pub struct Peer {
     pub port: Option<u16>,
     pub ip: Option<Vec<IpAddr>>,
     pub wg_interface: Option<String>,
+    /// Index of the local interface a link local endpoint is reached over, never taken from the server
+    #[serde(skip_deserializing)]
+    pub scope_id: Option<u32>,
+    /// Address of the local network the server matched the peer on, the only network a link
+    /// local endpoint can be reached over. Only set in peering responses.
+    #[serde(default, skip_serializing)]
+    pub local_ip: Option<IpAddr>,
+}
+
+/// Configuration of a peer that was configured before autopeering touched it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OriginalPeer {
+    pub endpoint: Option<SocketAddr>,
+    /// Allowed IPs in CIDR notation
+    pub allowed_ips: Vec<String>,
+    pub persistent_keepalive: u16,
+    /// Base64 encoded pre-shared key, the state file is only readable by its owner
+    #[serde(default)]
+    pub preshared_key: Option<String>,
+}
+
+/// Handshake and traffic statistics of a peer
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerStats {
+    /// Unix timestamp of the last handshake, 0 if there never was one
+    pub last_handshake: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Wireguard {
     pub pubkey: Option<String>,
     pub port: u16,
+    /// Mark of the encrypted packets, 0 if unset
+    pub fwmark: u32,
 }
 
 /// Network interface definition
@@ -39,6 +108,10 @@ pub struct NetworkInterface {
     pub net: Option<IpNet>,
     pub nexthop: Option<IpAddr>,
     pub is_default: bool,
+    /// Route metric of the network, lower is better
+    pub metric: Option<u32>,
+    /// Fingerprint of the layer 2 network, see `network_fingerprint`
+    pub fingerprint: Option<String>,
     pub peers: Vec<Peer>,
     pub wireguard: Option<Wireguard>,
 }
@@ -46,27 +119,307 @@ pub struct NetworkInterface {
 impl NetworkInterface {
     pub fn has_pubkey(&self) -> bool {
         if let Some(wg) = &self.wireguard {
-            if let Some(_) = &wg.pubkey {
-                true
-            } else {
-                false
-            }
+            wg.pubkey.is_some()
         } else {
             false
         }        
     }
+
+    /// Check if direct connections to peers can go over this network. IPv4 link local networks are
+    /// left out, they only exist when DHCP failed. IPv6 link local networks always exist and are
+    /// used with the scope id of the interface.
+    pub fn is_underlay(&self) -> bool {
+        match self.net {
+            Some(IpNet::V4(net)) => self.wireguard.is_none() && !is_link_local(&IpAddr::V4(net.addr())),
+            Some(IpNet::V6(_)) => self.wireguard.is_none(),
+            None => false,
+        }
+    }
 }
 
-/// Settings
+/// Check if an address is only valid on the local link (169.254.0.0/16 or fe80::/10)
+pub fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+    }
+}
+
+/// Index of the best local network to reach an endpoint: the most specific network containing it,
+/// then the one holding the default route, then the lowest metric. Only underlay interfaces that
+/// pass `filter` count.
+pub fn best_network(interfaces: &[NetworkInterface], filter: &InterfaceFilter, endpoint: IpAddr) -> Option<usize> {
+    interfaces
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.is_underlay() && filter.matches(&item.name) && item.net.is_some_and(|net| net.contains(&endpoint)))
+        .min_by_key(|(_, item)| (u8::MAX - item.net.unwrap().prefix_len(), !item.is_default, item.metric.unwrap_or(u32::MAX)))
+        .map(|(index, _)| index)
+}
+
+/// Index of the local network to reach a peer over, see `best_network`. A link local endpoint is
+/// only reached over the network the server matched the peer on.
+pub fn peer_network(interfaces: &[NetworkInterface], filter: &InterfaceFilter, peer: &Peer) -> Option<usize> {
+    let endpoint = peer.endpoint?;
+    if !is_link_local(&endpoint) {
+        return best_network(interfaces, filter, endpoint);
+    }
+    let local_ip = peer.local_ip?;
+    interfaces
+        .iter()
+        .position(|item| item.is_underlay() && filter.matches(&item.name) && item.net.is_some_and(|net| (net.addr() == local_ip) && net.contains(&endpoint)))
+}
+
+/// Pick one endpoint for every peer the server offered, IPv6 endpoints win over IPv4 endpoints of
+/// the same peer. Returns the peers with the index of the local network they are reached over,
+/// unreachable and skipped peers are left out.
+pub fn select_endpoints(peers: &[Peer], interfaces: &[NetworkInterface], filter: &InterfaceFilter, skip: impl Fn(&Peer) -> bool) -> Vec<(Peer, usize)> {
+    let mut result: Vec<(Peer, usize)> = vec![];
+
+    for peer in peers {
+        if skip(peer) {
+            continue;
+        }
+        let Some(index) = peer_network(interfaces, filter, peer) else {
+            continue;
+        };
+        // the network is known from here on
+        let peer = &Peer { local_ip: None, ..peer.clone() };
+
+        match result.iter_mut().find(|(item, _)| (item.pubkey == peer.pubkey) && (item.wg_interface == peer.wg_interface)) {
+            Some(selected) => {
+                if selected.0.endpoint.is_some_and(|endpoint| endpoint.is_ipv4()) && peer.endpoint.is_some_and(|endpoint| endpoint.is_ipv6()) {
+                    *selected = (peer.clone(), index);
+                }
+            }
+            None => result.push((peer.clone(), index)),
+        }
+    }
+
+    result
+}
+
+/// What to do with peers a previous run left on the wireguard interfaces
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReconcileMode {
+    /// Keep them if their endpoint is in a local network until the next peering query decides
+    #[default]
+    Adopt,
+    /// Remove all of them
+    Remove,
+}
+
+/// Protocol used to talk to the WireGuard-Web server
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+/// How to reach the WireGuard-Web server of one wireguard interface. The server is found by
+/// `url`, then `host`, then the SRV/TXT records of `domain` and finally the first ip of the network.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSettings {
+    /// Full URL of the server including the path prefix, e.g. `https://10.0.0.254/api`
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Host name or ip address of the server, combined with scheme, port and path
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Domain with the discovery records, defaults to the search domain of the system resolver
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// DNS server for host names and discovery records, defaults to the system name server
+    #[serde(default)]
+    pub dns_server: Option<IpAddr>,
+    #[serde(default)]
+    pub scheme: Scheme,
+    /// Port of the server, defaults to the port of the scheme
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Path prefix in front of the API endpoints, e.g. `/api`
+    #[serde(default)]
+    pub path: String,
+    /// PEM file with the CA certificates to trust instead of the built in ones
+    #[serde(default)]
+    pub ca_bundle: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate in hex, colons are allowed
+    #[serde(default)]
+    pub pinned_certificate: Option<String>,
+    /// Fail requests that can not be signed, by default once a request to the server was signed
+    #[serde(default)]
+    pub require_signature: Option<bool>,
+}
+
+impl ServerSettings {
+    /// Check settings for values that can not work
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(fingerprint) = &self.pinned_certificate {
+            parse_fingerprint(fingerprint)?;
+        }
+        let scheme = match &self.url {
+            Some(url) => match reqwest::Url::parse(url) {
+                Ok(url) if url.scheme() == "https" => Scheme::Https,
+                Ok(url) if url.scheme() == "http" => Scheme::Http,
+                Ok(_) => return Err(format!("{} is not a http or https URL", url)),
+                Err(error) => return Err(format!("{} is not a valid URL: {}", url, error)),
+            },
+            None => self.scheme,
+        };
+        if (scheme == Scheme::Http) && (self.ca_bundle.is_some() || self.pinned_certificate.is_some()) {
+            return Err("ca_bundle and pinned_certificate need scheme \"https\"".into());
+        }
+        if !self.path.is_empty() && !self.path.starts_with('/') {
+            return Err("path has to start with a slash".into());
+        }
+        Ok(())
+    }
+}
+
+/// Parse a SHA-256 fingerprint in hex notation, colons between the bytes are allowed
+pub fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], String> {
+    let digits: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    let invalid = || format!("{} is not a SHA-256 fingerprint", fingerprint);
+
+    if (digits.len() != 64) || !digits.is_ascii() {
+        return Err(invalid());
+    }
+    let mut result = [0u8; 32];
+    for (index, byte) in result.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[index * 2..index * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(result)
+}
+
+/// Timeouts and retries of requests to the servers
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSettings {
+    /// Seconds to wait for a connection to the server
+    pub connect_timeout: u64,
+    /// Seconds to wait for the whole request including the response
+    pub request_timeout: u64,
+    /// Attempts per request, failed connections, timeouts and server errors are retried
+    pub attempts: u32,
+    /// Milliseconds to wait before the first retry, doubled with every further attempt
+    pub retry_delay: u64,
+    /// Failed queries in a row before queries to the server are paused
+    pub failure_threshold: u32,
+    /// Seconds to pause queries to a failing server, doubled with every failed trial query
+    pub backoff: u64,
+    /// Longest pause in seconds
+    pub max_backoff: u64,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout: 5,
+            request_timeout: 15,
+            attempts: 3,
+            retry_delay: 500,
+            failure_threshold: 3,
+            backoff: 30,
+            max_backoff: 900,
+        }
+    }
+}
+
+/// Settings
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Settings {
     #[serde(default)]
     pub refresh_timeout: Timeout,
+    /// Milliseconds to wait for address changes to settle before the networks are compared
+    #[serde(default)]
+    pub debounce: Debounce,
+    /// Path of the control socket, defaults to /run/wireguard-web-autopeer/control.sock
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+    /// Permissions of the control socket, everyone with write access may control the daemon
+    #[serde(default)]
+    pub control_socket_mode: FileMode,
+    /// Path of the file that remembers added peers, defaults to /var/lib/wireguard-web-autopeer/peers.json
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    /// What to do with peers that were left over by a crashed daemon
+    #[serde(default)]
+    pub reconcile: ReconcileMode,
+    /// Keep added peers on the wireguard interfaces when shutting down for faster restarts
+    #[serde(default)]
+    pub leave_peers: bool,
+    /// Seconds to wait for a handshake with a new peer before falling back to the VPN server
+    #[serde(default)]
+    pub handshake_timeout: Timeout,
+    /// Seconds between two handshake checks
+    #[serde(default)]
+    pub health_check_interval: Timeout,
+    /// Server settings by wireguard interface name
+    #[serde(default)]
+    pub servers: HashMap<String, ServerSettings>,
+    /// Wireguard interfaces that get peering queries
+    #[serde(default)]
+    pub wireguard_interfaces: InterfaceFilter,
+    /// Interfaces whose networks may be sent to the servers
+    #[serde(default)]
+    pub underlay_interfaces: InterfaceFilter,
+    /// STUN servers as `host:port` to find the external address of each network, empty disables probing
+    #[serde(default)]
+    pub stun_servers: Vec<String>,
+    #[serde(default)]
+    pub http: HttpSettings,
 }
 
-impl Default for Settings {
-    fn default() -> Self {
-        Self { refresh_timeout: Timeout::default() }
+impl Settings {
+    /// Check settings for values that can not work
+    pub fn validate(&self) -> Result<(), String> {
+        if self.refresh_timeout.0 == 0 {
+            return Err("refresh_timeout has to be at least 1 second".into());
+        }
+        if self.health_check_interval.0 == 0 {
+            return Err("health_check_interval has to be at least 1 second".into());
+        }
+        if (self.http.connect_timeout == 0) || (self.http.request_timeout == 0) {
+            return Err("http.connect_timeout and http.request_timeout have to be greater than 0".into());
+        }
+        if (self.http.attempts == 0) || (self.http.failure_threshold == 0) {
+            return Err("http.attempts and http.failure_threshold have to be greater than 0".into());
+        }
+        for (name, server) in &self.servers {
+            server.validate().map_err(|error| format!("servers.{}: {}", name, error))?;
+        }
+        if self.control_socket_mode.0 > 0o777 {
+            return Err("control_socket_mode has to be a permission mode like 0o660".into());
+        }
+        Ok(())
+    }
+
+    /// Server settings of a wireguard interface
+    pub fn server(&self, interface: &str) -> ServerSettings {
+        self.servers.get(interface).cloned().unwrap_or_default()
+    }
+
+    /// Path of the control socket
+    pub fn control_socket_path(&self) -> PathBuf {
+        if let Some(path) = &self.control_socket {
+            return path.clone();
+        }
+
+        PathBuf::from("/run/wireguard-web-autopeer/control.sock")
+    }
+
+    /// Path of the state file
+    pub fn state_file_path(&self) -> PathBuf {
+        if let Some(path) = &self.state_file {
+            return path.clone();
+        }
+
+        PathBuf::from("/var/lib/wireguard-web-autopeer/peers.json")
     }
 }
 
@@ -77,16 +430,123 @@ pub struct StateManager {
     pub interfaces: Vec<NetworkInterface>,
     pub settings: Settings,
     pub suspended: bool,
+    /// Only log changes to wireguard peers, do not apply them
+    pub dry_run: bool,
+    /// Peers added to wireguard interfaces, persisted to disk
+    pub journal: Journal,
+    /// Health of the direct connections to managed peers and peers that failed
+    pub health: Vec<HealthRecord>,
+    /// Clients for the discovered servers by wireguard interface name
+    pub connections: HashMap<String, Connection>,
+    /// Circuit breakers of the servers by wireguard interface name
+    pub breakers: HashMap<String, CircuitBreaker>,
+    /// External addresses by local address of the underlay networks
+    pub public_addresses: HashMap<IpAddr, PublicAddress>,
+    /// Number of the latest query round
+    pub generation: u64,
+    /// Access to the wireguard devices
+    pub backend: Arc<dyn WireguardBackend>,
+    /// Wireguard interfaces whose server got a signed request, requests to their server have to be
+    /// signed from then on wherever the server is found. Shared with the running rounds.
+    pub signed: Arc<Mutex<HashSet<String>>>,
 }
 
 impl TryFrom<Peer> for SocketAddr {
     type Error = ();
 
     fn try_from(value: Peer) -> Result<Self, Self::Error> {
-        if let (Some(endpoint), Some(port)) = (value.endpoint, value.port) {
-            Ok(SocketAddr::new(endpoint, port))
-        } else {
-            Err(())
+        match (value.endpoint, value.port) {
+            (Some(IpAddr::V6(endpoint)), Some(port)) if endpoint.is_unicast_link_local() => {
+                Ok(SocketAddr::V6(SocketAddrV6::new(endpoint, port, 0, value.scope_id.unwrap_or(0))))
+            }
+            (Some(endpoint), Some(port)) => Ok(SocketAddr::new(endpoint, port)),
+            _ => Err(()),
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::state::filter::InterfaceFilter;
+    use crate::state::structs::{best_network, select_endpoints, NetworkInterface, Peer};
+    use crate::state::testing::{self, peer, wireguard};
+
+    fn interface(name: &str, net: &str, is_default: bool, metric: Option<u32>) -> NetworkInterface {
+        NetworkInterface { is_default, metric, ..testing::interface(name, net) }
+    }
+
+    #[test]
+    fn best_network_for_endpoint() {
+        let interfaces = vec![
+            wireguard("wg0", "192.168.0.2/16", "a"),
+            interface("wlan0", "192.168.1.20/24", false, Some(600)),
+            interface("eth0", "192.168.1.10/24", true, Some(100)),
+            interface("eth1", "10.0.0.2/8", false, Some(100)),
+            interface("eth2", "10.1.0.2/16", false, Some(700)),
+        ];
+
+        let all = InterfaceFilter::default();
+        assert_eq!(best_network(&interfaces, &all, "192.168.1.50".parse().unwrap()), Some(2));
+        assert_eq!(best_network(&interfaces, &all, "10.1.2.3".parse().unwrap()), Some(4));
+        assert_eq!(best_network(&interfaces, &all, "10.2.2.3".parse().unwrap()), Some(3));
+        assert_eq!(best_network(&interfaces, &all, "192.168.2.1".parse().unwrap()), None);
+
+        // excluded networks like docker bridges are never used
+        let filter = InterfaceFilter { include: vec![], exclude: vec!["eth2".into()] };
+        assert_eq!(best_network(&interfaces, &filter, "10.1.2.3".parse().unwrap()), Some(3));
+    }
+
+    #[test]
+    fn link_local_underlay() {
+        assert!(interface("eth0", "fe80::1/64", false, None).is_underlay());
+        assert!(!interface("eth0", "169.254.3.4/16", false, None).is_underlay());
+        assert!(interface("eth0", "fd00::1/64", false, None).is_underlay());
+    }
+
+    #[test]
+    fn ipv6_endpoint_wins() {
+        let interfaces = vec![
+            interface("eth0", "192.168.1.10/24", true, Some(100)),
+            interface("eth0", "fd00:1::10/64", false, Some(100)),
+        ];
+        let peers = vec![peer("a", "192.168.1.20"), peer("a", "fd00:1::20"), peer("b", "fd00:2::20"), peer("b", "192.168.1.21"), peer("c", "10.0.0.1")];
+
+        let selected = select_endpoints(&peers, &interfaces, &InterfaceFilter::default(), |_| false);
+        assert_eq!(selected, vec![(peer("a", "fd00:1::20"), 1), (peer("b", "192.168.1.21"), 0)]);
+
+        // a failed IPv6 endpoint falls back to IPv4
+        let selected = select_endpoints(&peers, &interfaces, &InterfaceFilter::default(), |peer| peer.endpoint == Some("fd00:1::20".parse().unwrap()));
+        assert_eq!(selected[0], (peer("a", "192.168.1.20"), 0));
+    }
+
+    #[test]
+    fn ipv6_only_lan() {
+        let interfaces = vec![
+            interface("eth0", "fe80::1234/64", false, Some(100)),
+            interface("eth0", "fd00:1::10/64", true, Some(100)),
+            interface("wlan0", "fe80::5678/64", false, Some(600)),
+        ];
+        let peers = vec![peer("a", "fd00:1::20"), peer("b", "fe80::20"), Peer { local_ip: Some("fe80::5678".parse().unwrap()), ..peer("c", "fe80::30") }];
+
+        // link local endpoints are only reachable over the network the server matched them on
+        let selected = select_endpoints(&peers, &interfaces, &InterfaceFilter::default(), |_| false);
+        assert_eq!(selected, vec![(peer("a", "fd00:1::20"), 1), (peer("c", "fe80::30"), 2)]);
+
+        let mut scoped = peer("b", "fe80::20");
+        scoped.scope_id = Some(3);
+        assert_eq!(SocketAddr::try_from(scoped).unwrap().to_string(), "[fe80::20%3]:51820");
+        assert_eq!(SocketAddr::try_from(peer("a", "fd00:1::20")).unwrap().to_string(), "[fd00:1::20]:51820");
+        // the scope only belongs to link local endpoints
+        let global = Peer { scope_id: Some(3), ..peer("a", "fd00:1::20") };
+        assert_eq!(SocketAddr::try_from(global).unwrap().to_string(), "[fd00:1::20]:51820");
+    }
+
+    #[test]
+    fn scope_not_taken_from_server() {
+        let peer: Peer = serde_json::from_str(r#"{"pubkey": "a", "endpoint": "fe80::20", "port": 51820, "ip": null, "wg_interface": null, "scope_id": 3}"#).unwrap();
+        assert_eq!(peer.scope_id, None);
+    }
+}
@@ -0,0 +1,83 @@
+//! Fixtures for tests, the structs of the state are spelled out only here
+
+use std::sync::Arc;
+
+use crate::http::client::{CircuitBreaker, CircuitState};
+use crate::wireguard::backend::MockBackend;
+
+use super::query::{QueryOutcome, QueryResults, QueryRound};
+use super::structs::{NetworkInterface, Peer, PeerStats, Settings, StateManager, Wireguard};
+
+/// Underlay network without default route and metric
+pub fn interface(name: &str, net: &str) -> NetworkInterface {
+    NetworkInterface {
+        name: name.into(),
+        net: Some(net.parse().unwrap()),
+        nexthop: None,
+        is_default: false,
+        metric: None,
+        fingerprint: None,
+        peers: vec![],
+        wireguard: None,
+    }
+}
+
+/// Network of a wireguard interface with a public key
+pub fn wireguard(name: &str, net: &str, pubkey: &str) -> NetworkInterface {
+    NetworkInterface {
+        wireguard: Some(Wireguard { pubkey: Some(pubkey.into()), port: 51820, fwmark: 0 }),
+        ..interface(name, net)
+    }
+}
+
+/// Peer of wg0 without allowed ips
+pub fn peer(pubkey: &str, endpoint: &str) -> Peer {
+    Peer {
+        pubkey: pubkey.into(),
+        endpoint: Some(endpoint.parse().unwrap()),
+        port: Some(51820),
+        ip: None,
+        wg_interface: Some("wg0".into()),
+        scope_id: None,
+        local_ip: None,
+    }
+}
+
+/// Statistics of a peer that received nothing
+pub fn stats(last_handshake: u64, tx_bytes: u64) -> PeerStats {
+    PeerStats { last_handshake, rx_bytes: 0, tx_bytes }
+}
+
+/// State with wg0 on 10.0.0.2/24 whose queries never go out, its journal is `file` in the temp dir
+/// so tests running at the same time do not share it
+pub fn state(file: &str) -> StateManager {
+    let path = std::env::temp_dir().join(file);
+    let _ = std::fs::remove_file(&path);
+    let mut state = StateManager::new(Settings { state_file: Some(path), ..Default::default() });
+    state.suspended = false;
+    state.interfaces.push(wireguard("wg0", "10.0.0.2/24", "a"));
+    // keep the round off the network
+    state.breakers.insert("wg0".into(), CircuitBreaker { state: CircuitState::Open, failures: 3, retry_at: Some(u64::MAX), last_error: None });
+    state
+}
+
+/// Mock wireguard device wg0 and the underlay network eth0 on 192.168.1.10/24 for a state
+pub fn devices(state: &mut StateManager) -> Arc<MockBackend> {
+    let backend = Arc::new(MockBackend::default());
+    backend.add_device("wg0", 1);
+    state.backend = backend.clone();
+    state.interfaces.push(interface("eth0", "192.168.1.10/24"));
+    backend
+}
+
+/// Results of a round in which the server of wg0 answered with `peers`
+pub fn answer(state: &StateManager, round: &QueryRound, peers: Vec<Peer>) -> QueryResults {
+    QueryResults {
+        generation: round.generation,
+        started: round.started,
+        public_addresses: Default::default(),
+        fingerprints: vec![],
+        outcomes: vec![QueryOutcome { interface: state.interfaces[0].clone(), connection: None, result: Ok(peers) }],
+        skipped: 0,
+    }
+}
@@ -1,12 +1,10 @@
 
 use tokio::sync::mpsc::{Sender, channel};
 use std::{fs, thread};
-use xdg;
 
 use crate::state::messages::Message;
 
 use super::Tray;
-use ksni;
 
 #[derive(Debug)]
 pub struct WireguardWebTray {
@@ -0,0 +1,602 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use wireguard_uapi::get::{AllowedIp, Device, Peer};
+
+use super::error::WireguardError;
+
+/// Directory of the control sockets of userspace implementations like wireguard-go and boringtun
+pub const UAPI_SOCKET_DIR: &str = "/var/run/wireguard";
+/// Seconds to wait for a response of a userspace implementation
+const UAPI_TIMEOUT: u64 = 5;
+
+/// Address families of allowed ips in `get::AllowedIp`, the values of Linux
+const AF_INET: u16 = 2;
+const AF_INET6: u16 = 10;
+
+
+/// Change of one peer, values that are not set are left as they are
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerConfig {
+    pub public_key: [u8; 32],
+    pub remove: bool,
+    /// Only change a peer that exists already
+    pub update_only: bool,
+    pub endpoint: Option<SocketAddr>,
+    /// Replace the allowed ips instead of adding to them
+    pub replace_allowed_ips: bool,
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+    /// All zeros removes the key
+    pub preshared_key: Option<[u8; 32]>,
+    /// 0 disables keepalives
+    pub persistent_keepalive: Option<u16>,
+}
+
+/// Access to wireguard devices
+pub trait WireguardBackend: fmt::Debug + Send + Sync {
+    /// Configuration and peers of a device
+    fn device(&self, name: &str) -> Result<Device, WireguardError>;
+
+    /// Add, update or remove a peer
+    fn set_peer(&self, name: &str, peer: &PeerConfig) -> Result<(), WireguardError>;
+
+    fn peers(&self, name: &str) -> Result<Vec<Peer>, WireguardError> {
+        Ok(self.device(name)?.peers)
+    }
+
+    fn remove_peer(&self, name: &str, public_key: &[u8; 32]) -> Result<(), WireguardError> {
+        self.set_peer(name, &PeerConfig { public_key: *public_key, remove: true, ..Default::default() })
+    }
+}
+
+/// Run calls of a backend on the blocking thread pool, netlink and UAPI calls wait for the kernel or
+/// the userspace implementation and must not stall the event loop
+pub async fn blocking<T, F>(backend: &Arc<dyn WireguardBackend>, call: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn WireguardBackend) -> T + Send + 'static,
+{
+    let backend = backend.clone();
+    tokio::task::spawn_blocking(move || call(backend.as_ref())).await.expect("wireguard backend call panicked")
+}
+
+fn allowed_ip(ipaddr: IpAddr, cidr_mask: u8) -> AllowedIp {
+    AllowedIp { family: if ipaddr.is_ipv4() { AF_INET } else { AF_INET6 }, ipaddr, cidr_mask }
+}
+
+
+/// Kernel module over generic netlink
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+pub struct KernelBackend;
+
+/// Check if a network interface is a wireguard device by its type in sysfs
+#[cfg(target_os = "linux")]
+fn is_wireguard_link(name: &str) -> bool {
+    let uevent = PathBuf::from("/sys/class/net").join(name).join("uevent");
+    std::fs::read_to_string(uevent).is_ok_and(|content| is_wireguard_uevent(&content))
+}
+
+#[cfg(any(target_os = "linux", test))]
+fn is_wireguard_uevent(content: &str) -> bool {
+    content.lines().any(|line| line.trim() == "DEVTYPE=wireguard")
+}
+
+#[cfg(target_os = "linux")]
+impl KernelBackend {
+    fn connect(name: &str) -> Result<wireguard_uapi::WgSocket, WireguardError> {
+        use wireguard_uapi::err::ConnectError;
+
+        wireguard_uapi::WgSocket::connect().map_err(|error| match error {
+            ConnectError::NlError(error) => Self::netlink_error(name, error),
+            ConnectError::ResolveFamilyError(_) => WireguardError::Unavailable("the wireguard kernel module is not loaded".to_string()),
+        })
+    }
+
+    fn netlink_error(name: &str, error: neli::err::NlError) -> WireguardError {
+        use neli::err::{NlError, WrappedError};
+
+        match error {
+            // netlink reports negative error numbers
+            NlError::Nlmsgerr(error) => WireguardError::from_errno(name, -error.error),
+            NlError::Wrapped(WrappedError::IOError(error)) => WireguardError::from_io(name, &error),
+            error => WireguardError::Unavailable(format!("{}: {}", name, error)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl WireguardBackend for KernelBackend {
+    fn device(&self, name: &str) -> Result<Device, WireguardError> {
+        use wireguard_uapi::err::GetDeviceError;
+        use wireguard_uapi::DeviceInterface;
+
+        let mut wg = Self::connect(name)?;
+        wg.get_device(DeviceInterface::from_name(name)).map_err(|error| match error {
+            GetDeviceError::NlError(error) => Self::netlink_error(name, error),
+            // the kernel answers the same to a missing device, an interface of another kind and a
+            // missing capability
+            GetDeviceError::AccessError | GetDeviceError::InvalidInterfaceName => match is_wireguard_link(name) {
+                true => WireguardError::PermissionDenied(name.to_string()),
+                false => WireguardError::NoSuchDevice(name.to_string()),
+            },
+            error => WireguardError::Unavailable(format!("{}: {}", name, error)),
+        })
+    }
+
+    fn set_peer(&self, name: &str, peer: &PeerConfig) -> Result<(), WireguardError> {
+        use wireguard_uapi::err::SetDeviceError;
+        use wireguard_uapi::set::{self, WgPeerF};
+        use wireguard_uapi::DeviceInterface;
+
+        let mut wg = Self::connect(name)?;
+
+        let mut flags = vec![];
+        if peer.remove {
+            flags.push(WgPeerF::RemoveMe);
+        }
+        if peer.update_only {
+            flags.push(WgPeerF::UpdateOnly);
+        }
+        if peer.replace_allowed_ips {
+            flags.push(WgPeerF::ReplaceAllowedIps);
+        }
+        let update = set::Peer {
+            public_key: &peer.public_key,
+            flags,
+            preshared_key: peer.preshared_key.as_ref(),
+            endpoint: peer.endpoint.as_ref(),
+            persistent_keepalive_interval: peer.persistent_keepalive,
+            allowed_ips: peer.allowed_ips.iter().map(|(ip, cidr)| set::AllowedIp { ipaddr: ip, cidr_mask: Some(*cidr) }).collect(),
+            protocol_version: None,
+        };
+
+        let device = set::Device {
+            interface: DeviceInterface::from_name(name),
+            flags: vec![],
+            private_key: None,
+            listen_port: None,
+            fwmark: None,
+            peers: vec![update],
+        };
+        wg.set_device(device).map_err(|error| match error {
+            SetDeviceError::NlError(error) => Self::netlink_error(name, error),
+            error => WireguardError::Unavailable(format!("{}: {}", name, error)),
+        })
+    }
+}
+
+
+/// Userspace implementation speaking the cross-platform configuration protocol on a unix socket,
+/// see https://www.wireguard.com/xplatform/
+#[derive(Debug)]
+pub struct UapiBackend {
+    pub socket_dir: PathBuf,
+    /// Longest time to wait for a hung userspace implementation, requests run on the event loop
+    pub timeout: Duration,
+}
+
+impl Default for UapiBackend {
+    fn default() -> Self {
+        Self { socket_dir: PathBuf::from(UAPI_SOCKET_DIR), timeout: Duration::from_secs(UAPI_TIMEOUT) }
+    }
+}
+
+impl UapiBackend {
+    pub fn socket_path(&self, name: &str) -> PathBuf {
+        self.socket_dir.join(format!("{}.sock", name))
+    }
+
+    /// Send a request and return the key value pairs of the response, a response with an error
+    /// number is an error
+    #[cfg(unix)]
+    fn request(&self, name: &str, request: &str) -> Result<Vec<(String, String)>, WireguardError> {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixStream;
+
+        let path = self.socket_path(name);
+        let mut stream = UnixStream::connect(&path).map_err(|error| WireguardError::from_io(name, &error))?;
+        stream.set_read_timeout(Some(self.timeout)).and_then(|_| stream.set_write_timeout(Some(self.timeout))).map_err(|error| WireguardError::from_io(name, &error))?;
+        stream.write_all(request.as_bytes()).map_err(|error| WireguardError::from_io(name, &error))?;
+
+        let mut pairs = vec![];
+        for line in BufReader::new(stream).lines() {
+            let line = line.map_err(|error| WireguardError::from_io(name, &error))?;
+            if line.is_empty() {
+                break;
+            }
+            let (key, value) = line.split_once('=').ok_or(WireguardError::Unavailable(format!("Invalid line in response of {}: {}", name, line)))?;
+            pairs.push((key.to_string(), value.to_string()));
+        }
+
+        match pairs.iter().find(|(key, _)| key == "errno") {
+            Some((_, errno)) if errno != "0" => match errno.parse() {
+                Ok(errno) => Err(WireguardError::from_errno(name, errno)),
+                Err(_) => Err(WireguardError::Unavailable(format!("Invalid errno {} of device {}", errno, name))),
+            },
+            Some(_) => Ok(pairs),
+            None => Err(WireguardError::Unavailable(format!("Incomplete response of device {}", name))),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn request(&self, name: &str, _request: &str) -> Result<Vec<(String, String)>, WireguardError> {
+        Err(WireguardError::Unavailable(format!("Userspace device {} is not supported on this platform", name)))
+    }
+}
+
+fn parse_key(value: &str) -> Result<[u8; 32], WireguardError> {
+    let invalid = || WireguardError::MalformedKey(value.to_string());
+    if (value.len() != 64) || !value.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0u8; 32];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[index * 2..index * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+fn format_key(key: &[u8; 32]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode the response to a get request
+fn parse_device(name: &str, pairs: &[(String, String)]) -> Result<Device, WireguardError> {
+    let invalid = |key: &str, value: &str| WireguardError::Unavailable(format!("Invalid {} {} of device {}", key, value, name));
+    let number = |key: &str, value: &str| value.parse::<u64>().map_err(|_| invalid(key, value));
+
+    let mut device = Device {
+        ifindex: 0,
+        ifname: name.to_string(),
+        private_key: None,
+        public_key: None,
+        listen_port: 0,
+        fwmark: 0,
+        peers: vec![],
+    };
+    for (key, value) in pairs {
+        let peer = device.peers.last_mut();
+        match (key.as_str(), peer) {
+            ("private_key", _) => {
+                let private_key = parse_key(value)?;
+                let secret = x25519_dalek::StaticSecret::from(private_key);
+                device.public_key = Some(x25519_dalek::PublicKey::from(&secret).to_bytes());
+                device.private_key = Some(private_key);
+            }
+            ("listen_port", _) => device.listen_port = number(key, value)? as u16,
+            ("fwmark", _) => device.fwmark = number(key, value)? as u32,
+            ("public_key", _) => device.peers.push(Peer {
+                public_key: parse_key(value)?,
+                preshared_key: [0u8; 32],
+                endpoint: None,
+                persistent_keepalive_interval: 0,
+                last_handshake_time: Default::default(),
+                rx_bytes: 0,
+                tx_bytes: 0,
+                allowed_ips: vec![],
+                protocol_version: 0,
+            }),
+            ("preshared_key", Some(peer)) => peer.preshared_key = parse_key(value)?,
+            ("endpoint", Some(peer)) => {
+                peer.endpoint = Some(value.parse().map_err(|_| invalid(key, value))?);
+            }
+            ("persistent_keepalive_interval", Some(peer)) => peer.persistent_keepalive_interval = number(key, value)? as u16,
+            ("last_handshake_time_sec", Some(peer)) => {
+                peer.last_handshake_time += Duration::from_secs(number(key, value)?);
+            }
+            ("last_handshake_time_nsec", Some(peer)) => {
+                peer.last_handshake_time += Duration::from_nanos(number(key, value)?);
+            }
+            ("rx_bytes", Some(peer)) => peer.rx_bytes = number(key, value)?,
+            ("tx_bytes", Some(peer)) => peer.tx_bytes = number(key, value)?,
+            ("protocol_version", Some(peer)) => peer.protocol_version = number(key, value)? as u32,
+            ("allowed_ip", Some(peer)) => {
+                let (ip, cidr) = value.split_once('/').ok_or(invalid(key, value))?;
+                let ip: IpAddr = ip.parse().map_err(|_| invalid(key, value))?;
+                peer.allowed_ips.push(allowed_ip(ip, number(key, cidr)? as u8));
+            }
+            _ => {}
+        }
+    }
+    Ok(device)
+}
+
+/// Encode a set request for one peer
+fn format_set_request(peer: &PeerConfig) -> String {
+    let mut request = format!("set=1\npublic_key={}\n", format_key(&peer.public_key));
+    if peer.remove {
+        request.push_str("remove=true\n");
+    }
+    if peer.update_only {
+        request.push_str("update_only=true\n");
+    }
+    if let Some(preshared_key) = &peer.preshared_key {
+        request.push_str(&format!("preshared_key={}\n", format_key(preshared_key)));
+    }
+    if let Some(endpoint) = &peer.endpoint {
+        request.push_str(&format!("endpoint={}\n", endpoint));
+    }
+    if let Some(keepalive) = peer.persistent_keepalive {
+        request.push_str(&format!("persistent_keepalive_interval={}\n", keepalive));
+    }
+    if peer.replace_allowed_ips {
+        request.push_str("replace_allowed_ips=true\n");
+    }
+    for (ip, cidr) in &peer.allowed_ips {
+        request.push_str(&format!("allowed_ip={}/{}\n", ip, cidr));
+    }
+    request.push('\n');
+    request
+}
+
+impl WireguardBackend for UapiBackend {
+    fn device(&self, name: &str) -> Result<Device, WireguardError> {
+        parse_device(name, &self.request(name, "get=1\n\n")?)
+    }
+
+    fn set_peer(&self, name: &str, peer: &PeerConfig) -> Result<(), WireguardError> {
+        self.request(name, &format_set_request(peer)).map(|_| ())
+    }
+}
+
+
+/// Uses the userspace implementation for devices that have a control socket, the kernel for all others
+#[derive(Debug, Default)]
+pub struct SystemBackend {
+    pub uapi: UapiBackend,
+    #[cfg(target_os = "linux")]
+    pub kernel: KernelBackend,
+}
+
+impl SystemBackend {
+    fn backend(&self, name: &str) -> &dyn WireguardBackend {
+        #[cfg(target_os = "linux")]
+        if !self.uapi.socket_path(name).exists() {
+            return &self.kernel;
+        }
+        &self.uapi
+    }
+}
+
+impl WireguardBackend for SystemBackend {
+    fn device(&self, name: &str) -> Result<Device, WireguardError> {
+        self.backend(name).device(name)
+    }
+
+    fn set_peer(&self, name: &str, peer: &PeerConfig) -> Result<(), WireguardError> {
+        self.backend(name).set_peer(name, peer)
+    }
+}
+
+
+/// Devices in memory for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    pub devices: std::sync::Mutex<std::collections::HashMap<String, Device>>,
+    /// Error of every change, a device without permissions
+    pub error: std::sync::Mutex<Option<WireguardError>>,
+    /// Error of every read
+    pub read_error: std::sync::Mutex<Option<WireguardError>>,
+}
+
+#[cfg(test)]
+impl MockBackend {
+    /// Add a device with a key pair derived from `seed`
+    pub fn add_device(&self, name: &str, seed: u8) {
+        let secret = x25519_dalek::StaticSecret::from([seed; 32]);
+        self.devices.lock().unwrap().insert(name.to_string(), Device {
+            ifindex: 0,
+            ifname: name.to_string(),
+            private_key: Some(secret.to_bytes()),
+            public_key: Some(x25519_dalek::PublicKey::from(&secret).to_bytes()),
+            listen_port: 51820,
+            fwmark: 0,
+            peers: vec![],
+        });
+    }
+}
+
+#[cfg(test)]
+impl WireguardBackend for MockBackend {
+    fn device(&self, name: &str) -> Result<Device, WireguardError> {
+        if let Some(error) = self.read_error.lock().unwrap().clone() {
+            return Err(error);
+        }
+        let devices = self.devices.lock().unwrap();
+        let device = devices.get(name).ok_or(WireguardError::NoSuchDevice(name.to_string()))?;
+        Ok(Device { ifname: device.ifname.clone(), peers: device.peers.clone(), ..*device })
+    }
+
+    fn set_peer(&self, name: &str, config: &PeerConfig) -> Result<(), WireguardError> {
+        if let Some(error) = self.error.lock().unwrap().clone() {
+            return Err(error);
+        }
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.get_mut(name).ok_or(WireguardError::NoSuchDevice(name.to_string()))?;
+
+        let index = device.peers.iter().position(|peer| peer.public_key == config.public_key);
+        if config.remove {
+            if let Some(index) = index {
+                device.peers.remove(index);
+            }
+            return Ok(());
+        }
+        let peer = match index {
+            Some(index) => &mut device.peers[index],
+            None if config.update_only => return Ok(()),
+            None => {
+                device.peers.push(Peer {
+                    public_key: config.public_key,
+                    preshared_key: [0u8; 32],
+                    endpoint: None,
+                    persistent_keepalive_interval: 0,
+                    last_handshake_time: Default::default(),
+                    rx_bytes: 0,
+                    tx_bytes: 0,
+                    allowed_ips: vec![],
+                    protocol_version: 1,
+                });
+                device.peers.last_mut().unwrap()
+            }
+        };
+
+        if let Some(preshared_key) = config.preshared_key {
+            peer.preshared_key = preshared_key;
+        }
+        if config.endpoint.is_some() {
+            peer.endpoint = config.endpoint;
+        }
+        if let Some(keepalive) = config.persistent_keepalive {
+            peer.persistent_keepalive_interval = keepalive;
+        }
+        if config.replace_allowed_ips {
+            peer.allowed_ips.clear();
+        }
+        for (ip, cidr) in &config.allowed_ips {
+            if !peer.allowed_ips.iter().any(|item| (item.ipaddr == *ip) && (item.cidr_mask == *cidr)) {
+                peer.allowed_ips.push(allowed_ip(*ip, *cidr));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::wireguard::backend::{format_set_request, is_wireguard_uevent, parse_device, MockBackend, PeerConfig, UapiBackend, WireguardBackend};
+    use crate::wireguard::error::WireguardError;
+
+    fn pairs(text: &str) -> Vec<(String, String)> {
+        text.lines().filter_map(|line| line.split_once('=')).map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parse_get_response() {
+        let response = pairs("private_key=e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a\n\
+            listen_port=12912\n\
+            fwmark=51820\n\
+            public_key=b85996fecc9c7f1fc6d2572a76eda11d59bcd20be8e543b15ce4bd85a8e75a33\n\
+            preshared_key=188515093e952f5f22e865cef3012e72f8b5f0b598ac0309d5dacce3b70fcf52\n\
+            allowed_ip=192.168.4.4/32\n\
+            endpoint=[abcd:23::33]:51820\n\
+            last_handshake_time_sec=1\n\
+            last_handshake_time_nsec=500000000\n\
+            rx_bytes=2224\n\
+            tx_bytes=38333\n\
+            persistent_keepalive_interval=25\n\
+            public_key=58402e695ba1772b1cc9309755f043251ea77fdcf10fbe63989ceae4ce4c2b0c\n\
+            allowed_ip=fd00::/64\n\
+            allowed_ip=10.0.0.0/8\n\
+            errno=0\n");
+        let device = parse_device("wg0", &response).unwrap();
+        assert_eq!(device.listen_port, 12912);
+        assert_eq!(device.fwmark, 51820);
+        assert!(device.public_key.is_some());
+        assert_eq!(device.peers.len(), 2);
+        assert_eq!(device.peers[0].endpoint, Some("[abcd:23::33]:51820".parse().unwrap()));
+        assert_eq!(device.peers[0].last_handshake_time.as_millis(), 1500);
+        assert_eq!(device.peers[0].rx_bytes, 2224);
+        assert_eq!(device.peers[0].persistent_keepalive_interval, 25);
+        assert_eq!(device.peers[1].allowed_ips.len(), 2);
+        assert_eq!(device.peers[1].allowed_ips[1].cidr_mask, 8);
+
+        assert_eq!(parse_device("wg0", &pairs("public_key=zz\n")).unwrap_err(), WireguardError::MalformedKey("zz".into()));
+    }
+
+    #[test]
+    fn wireguard_uevent() {
+        assert!(is_wireguard_uevent("DEVTYPE=wireguard\nINTERFACE=wg0\nIFINDEX=5\n"));
+        assert!(!is_wireguard_uevent("INTERFACE=eth0\nIFINDEX=2\n"));
+        assert!(!is_wireguard_uevent("DEVTYPE=bridge\nINTERFACE=docker0\nIFINDEX=3\n"));
+    }
+
+    #[test]
+    fn set_request() {
+        let peer = PeerConfig {
+            public_key: [0xab; 32],
+            endpoint: Some("192.168.1.20:51820".parse().unwrap()),
+            allowed_ips: vec![("10.0.0.2".parse().unwrap(), 32)],
+            ..Default::default()
+        };
+        assert_eq!(format_set_request(&peer), format!("set=1\npublic_key={}\nendpoint=192.168.1.20:51820\nallowed_ip=10.0.0.2/32\n\n", "ab".repeat(32)));
+
+        let remove = PeerConfig { public_key: [0xab; 32], remove: true, ..Default::default() };
+        assert_eq!(format_set_request(&remove), format!("set=1\npublic_key={}\nremove=true\n\n", "ab".repeat(32)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn uapi_socket() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixListener;
+
+        // stand-in for wireguard-go that answers one get and one set request, then hangs
+        let dir = std::env::temp_dir().join(format!("uapi-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let backend = UapiBackend { socket_dir: dir.clone(), timeout: Duration::from_millis(100) };
+        let _ = std::fs::remove_file(backend.socket_path("wg9"));
+        let listener = UnixListener::bind(backend.socket_path("wg9")).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut requests = vec![];
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    request.push_str(&line);
+                    if line == "\n" {
+                        break;
+                    }
+                }
+                let response = match request.starts_with("get=1") {
+                    true => "listen_port=51821\nerrno=0\n\n",
+                    false => "errno=22\n\n",
+                };
+                (&stream).write_all(response.as_bytes()).unwrap();
+                requests.push(request);
+            }
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = std::io::Read::read_to_end(&mut stream, &mut vec![]);
+            requests
+        });
+
+        assert_eq!(backend.device("wg9").unwrap().listen_port, 51821);
+        assert_eq!(backend.remove_peer("wg9", &[1; 32]), Err(WireguardError::Rejected("wg9".into(), 22)));
+        assert!(matches!(backend.device("wg9"), Err(WireguardError::Unavailable(_))));
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0], "get=1\n\n");
+        assert!(requests[1].ends_with("remove=true\n\n"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn mock_devices() {
+        let backend = MockBackend::default();
+        backend.add_device("wg0", 1);
+        assert_eq!(backend.device("wg1").unwrap_err(), WireguardError::NoSuchDevice("wg1".into()));
+
+        let peer = PeerConfig { public_key: [2; 32], allowed_ips: vec![("10.0.0.2".parse().unwrap(), 32)], ..Default::default() };
+        backend.set_peer("wg0", &peer).unwrap();
+        backend.set_peer("wg0", &PeerConfig { replace_allowed_ips: true, allowed_ips: vec![("10.0.0.3".parse().unwrap(), 32)], ..peer.clone() }).unwrap();
+        let peers = backend.peers("wg0").unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].allowed_ips.len(), 1);
+
+        // update only does not add peers
+        backend.set_peer("wg0", &PeerConfig { public_key: [3; 32], update_only: true, ..Default::default() }).unwrap();
+        assert_eq!(backend.peers("wg0").unwrap().len(), 1);
+
+        backend.remove_peer("wg0", &[2; 32]).unwrap();
+        assert!(backend.peers("wg0").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+use std::io;
+
+/// Error numbers of Linux that have their own variant, userspace implementations use the same values
+const EPERM: i32 = 1;
+const ENOENT: i32 = 2;
+const EACCES: i32 = 13;
+const ENODEV: i32 = 19;
+
+
+/// Failure to read or change a wireguard device
+#[derive(Clone, Debug, PartialEq)]
+pub enum WireguardError {
+    /// Missing CAP_NET_ADMIN or access to the control socket
+    PermissionDenied(String),
+    /// The device does not exist or is no wireguard device
+    NoSuchDevice(String),
+    /// A key that is no base64 or hex encoded 32 byte key
+    MalformedKey(String),
+    /// The kernel or userspace implementation refused the change, with its error number
+    Rejected(String, i32),
+    /// The backend could not be reached or sent an invalid response
+    Unavailable(String),
+}
+
+impl WireguardError {
+    /// Error of a request to `device` that failed with the (positive) error number `errno`
+    pub fn from_errno(device: &str, errno: i32) -> Self {
+        match errno {
+            EPERM | EACCES => WireguardError::PermissionDenied(device.to_string()),
+            ENOENT | ENODEV => WireguardError::NoSuchDevice(device.to_string()),
+            _ => WireguardError::Rejected(device.to_string(), errno),
+        }
+    }
+
+    /// Error of opening the socket of `device`
+    pub fn from_io(device: &str, error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied => WireguardError::PermissionDenied(device.to_string()),
+            io::ErrorKind::NotFound => WireguardError::NoSuchDevice(device.to_string()),
+            _ => WireguardError::Unavailable(format!("{}: {}", device, error)),
+        }
+    }
+}
+
+impl fmt::Display for WireguardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireguardError::PermissionDenied(device) => write!(f, "Permission denied on device {}, CAP_NET_ADMIN is required", device),
+            WireguardError::NoSuchDevice(device) => write!(f, "No wireguard device {}", device),
+            WireguardError::MalformedKey(key) => write!(f, "Malformed key {}", key),
+            WireguardError::Rejected(device, errno) => write!(f, "Device {} rejected the change with errno {}", device, errno),
+            WireguardError::Unavailable(error) => write!(f, "Wireguard is unavailable: {}", error),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::wireguard::error::WireguardError;
+
+    #[test]
+    fn error_numbers() {
+        assert_eq!(WireguardError::from_errno("wg0", 1), WireguardError::PermissionDenied("wg0".into()));
+        assert_eq!(WireguardError::from_errno("wg0", 19), WireguardError::NoSuchDevice("wg0".into()));
+        assert_eq!(WireguardError::from_errno("wg0", 22), WireguardError::Rejected("wg0".into(), 22));
+        assert_eq!(WireguardError::from_io("wg0", &io::Error::from(io::ErrorKind::NotFound)), WireguardError::NoSuchDevice("wg0".into()));
+        assert_eq!(WireguardError::Rejected("wg0".into(), 22).to_string(), "Device wg0 rejected the change with errno 22");
+    }
+}
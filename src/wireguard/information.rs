@@ -1,123 +1,153 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
-use wireguard_uapi::{DeviceInterface, WgSocket, set::WgPeerF};
+use if_watch::IpNet;
 use base64::{Engine as _, engine::general_purpose};
 
-use wireguard_uapi::set::{AllowedIp, Device, Peer};
+use crate::state::structs::{Wireguard, OriginalPeer, PeerStats, self};
 
+use super::backend::{PeerConfig, WireguardBackend};
+use super::error::WireguardError;
 
-use crate::state::structs::{Wireguard, self};
+pub fn query_wg_info(backend: &dyn WireguardBackend, device_name: &str) -> Option<Wireguard> {
+    let device = match backend.device(device_name) {
+        Ok(device) => device,
+        // most interfaces are no wireguard devices
+        Err(WireguardError::NoSuchDevice(_)) => return None,
+        Err(error) => {
+            warn!("Could not read wireguard device {}: {}", device_name, error);
+            return None;
+        }
+    };
+    Some(Wireguard {
+        pubkey: device.public_key.map(|pubkey| general_purpose::STANDARD.encode(pubkey)),
+        port: device.listen_port,
+        fwmark: device.fwmark,
+    })
+}
+
+/// Public keys of all peers configured on a wireguard interface
+pub fn list_peers(backend: &dyn WireguardBackend, device_name: &str) -> Result<Vec<String>, WireguardError> {
+    let peers = backend.peers(device_name)?;
+    Ok(peers.iter().map(|peer| general_purpose::STANDARD.encode(peer.public_key)).collect())
+}
+
+/// Public keys and allowed ips of all peers configured on a wireguard interface
+pub fn peer_allowed_ips(backend: &dyn WireguardBackend, device_name: &str) -> Result<Vec<(String, Vec<IpNet>)>, WireguardError> {
+    let peers = backend.peers(device_name)?;
+    Ok(peers
+        .iter()
+        .map(|peer| {
+            let allowed_ips = peer.allowed_ips.iter().filter_map(|ip| IpNet::new(ip.ipaddr, ip.cidr_mask).ok()).collect();
+            (general_purpose::STANDARD.encode(peer.public_key), allowed_ips)
+        })
+        .collect())
+}
+
+/// Configuration of a peer that is already configured on a wireguard interface
+pub fn get_peer(backend: &dyn WireguardBackend, device_name: &str, pubkey: &str) -> Option<OriginalPeer> {
+    let peers = backend.peers(device_name).ok()?;
+    let peer = peers.iter().find(|peer| general_purpose::STANDARD.encode(peer.public_key) == pubkey)?;
+    Some(OriginalPeer {
+        endpoint: peer.endpoint,
+        allowed_ips: peer.allowed_ips.iter().map(|ip| format!("{}/{}", ip.ipaddr, ip.cidr_mask)).collect(),
+        persistent_keepalive: peer.persistent_keepalive_interval,
+        preshared_key: if peer.preshared_key == [0u8; 32] {
+            None
+        } else {
+            Some(general_purpose::STANDARD.encode(peer.preshared_key))
+        },
+    })
+}
 
-pub fn query_wg_info(device_name: &String) -> Option<Wireguard> {
-    if let Ok(mut wg) = WgSocket::connect() {
-        if let Ok(device) = wg.get_device(DeviceInterface::from_name(device_name)) {
-            if let Some(pubkey) = device.public_key {
-                return Some(Wireguard {
-                    pubkey: Some(general_purpose::STANDARD.encode(pubkey)),
-                    port: device.listen_port
-                })
-            } else {
-                return Some(Wireguard{pubkey: None, port: device.listen_port});
+/// Handshake and traffic statistics of a peer on a wireguard interface
+pub fn peer_stats(backend: &dyn WireguardBackend, device_name: &str, pubkey: &str) -> Option<PeerStats> {
+    let peers = backend.peers(device_name).ok()?;
+    let peer = peers.iter().find(|peer| general_purpose::STANDARD.encode(peer.public_key) == pubkey)?;
+    Some(PeerStats {
+        last_handshake: peer.last_handshake_time.as_secs(),
+        rx_bytes: peer.rx_bytes,
+        tx_bytes: peer.tx_bytes,
+    })
+}
+
+/// Private key of a wireguard interface and public key of the peer that routes `server_ip`,
+/// used to derive the request signing key
+pub fn signing_keys(backend: &dyn WireguardBackend, device_name: &str, server_ip: IpAddr) -> Option<([u8; 32], [u8; 32])> {
+    let device = backend.device(device_name).ok()?;
+    let private_key = device.private_key?;
+
+    // the most specific allowed ip wins, like in the wireguard routing table
+    let mut best: Option<(u8, [u8; 32])> = None;
+    for peer in &device.peers {
+        for allowed_ip in &peer.allowed_ips {
+            if let Ok(net) = IpNet::new(allowed_ip.ipaddr, allowed_ip.cidr_mask) {
+                if net.contains(&server_ip) && best.is_none_or(|(prefix, _)| prefix < allowed_ip.cidr_mask) {
+                    best = Some((allowed_ip.cidr_mask, peer.public_key));
+                }
             }
         }
     }
-    None
+
+    best.map(|(_, server_pubkey)| (private_key, server_pubkey))
 }
 
-pub fn add_peer<'a>(peer: structs::Peer) -> Result<(), wireguard_uapi::err::SetDeviceError> {
-    if let Ok(mut wg) = WgSocket::connect() {
-        let wg_interface = peer.wg_interface.unwrap();
-        let interface = DeviceInterface::from_name(wg_interface);
-        let key = general_purpose::STANDARD.decode(peer.pubkey).unwrap();
-
-        // convert values
-        let k: [u8; 32] = key.try_into().unwrap();
-        let mut p = Peer{
-            public_key: &k,
-            flags: vec![],
-            preshared_key: None,
-            endpoint: None,
-            persistent_keepalive_interval: None,
-            allowed_ips: vec![],
-            protocol_version: None
-        };
-        
-        // If we have an endpoit set it
-        let socket: SocketAddr;
-        if let (Some(endpoint), Some(port)) = (peer.endpoint, peer.port) {
-            socket = SocketAddr::new(endpoint, port);
-            p.endpoint = Some(&socket);
-        }
-        
-        // If we have allowed ips, set them
-        if let Some(ips) = &peer.ip {
-            let mut allowed_ips: Vec<AllowedIp> = vec![];
-            for ip in ips {
-                allowed_ips.push(AllowedIp::from_ipaddr(ip));
-            }
-            p.allowed_ips = allowed_ips;
-        }
-        
-        // create a query
-        let dev = Device {
-            interface,
-            flags: vec![],
-            private_key: None,
-            listen_port: None,
-            fwmark: None,
-            peers: vec![p],
-        };
-
-        return wg.set_device(dev);
+/// Decode a base64 encoded key
+pub fn decode_key(key: &str) -> Result<[u8; 32], WireguardError> {
+    general_purpose::STANDARD
+        .decode(key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or(WireguardError::MalformedKey(key.to_string()))
+}
+
+pub fn add_peer(backend: &dyn WireguardBackend, device_name: &str, peer: &structs::Peer) -> Result<(), WireguardError> {
+    // convert values
+    let mut config = PeerConfig { public_key: decode_key(&peer.pubkey)?, ..Default::default() };
+
+    // If we have an endpoit set it
+    if let Ok(address) = SocketAddr::try_from(peer.clone()) {
+        config.endpoint = Some(address);
     }
-    return Ok(());
+
+    // If we have allowed ips, set them as host routes
+    if let Some(ips) = &peer.ip {
+        config.allowed_ips = ips.iter().map(|ip| (*ip, if ip.is_ipv4() { 32 } else { 128 })).collect();
+    }
+
+    backend.set_peer(device_name, &config)
 }
 
-pub fn remove_peer(peer: structs::Peer) -> Result<(), wireguard_uapi::err::SetDeviceError> {
-    if let Ok(mut wg) = WgSocket::connect() {
-        let wg_interface = peer.wg_interface.unwrap();
-        let interface = DeviceInterface::from_name(wg_interface);
-        let key = general_purpose::STANDARD.decode(peer.pubkey).unwrap();
-
-        // convert values
-        let k: [u8; 32] = key.try_into().unwrap();
-        let mut p = Peer{
-            public_key: &k,
-            flags: vec![WgPeerF::RemoveMe],
-            preshared_key: None,
-            endpoint: None,
-            persistent_keepalive_interval: None,
-            allowed_ips: vec![],
-            protocol_version: None
-        };
-        
-        // If we have an endpoit set it
-        let socket: SocketAddr;
-        if let (Some(endpoint), Some(port)) = (peer.endpoint, peer.port) {
-            socket = SocketAddr::new(endpoint, port);
-            p.endpoint = Some(&socket);
-        }
-        
-        // If we have allowed ips, set them
-        if let Some(ips) = &peer.ip {
-            let mut allowed_ips: Vec<AllowedIp> = vec![];
-            for ip in ips {
-                allowed_ips.push(AllowedIp::from_ipaddr(ip));
+pub fn remove_peer(backend: &dyn WireguardBackend, device_name: &str, peer: &structs::Peer) -> Result<(), WireguardError> {
+    backend.remove_peer(device_name, &decode_key(&peer.pubkey)?)
+}
+
+/// Restore the configuration a peer had before autopeering touched it, a peer that is gone is
+/// added again. All values are replaced in one change so the peer is never missing.
+///
+/// The kernel can not clear an endpoint, so a peer that had no endpoint keeps the last one.
+pub fn restore_peer(backend: &dyn WireguardBackend, device_name: &str, pubkey: &str, original: &OriginalPeer) -> Result<(), WireguardError> {
+    // convert values
+    let mut config = PeerConfig {
+        public_key: decode_key(pubkey)?,
+        endpoint: original.endpoint,
+        replace_allowed_ips: true,
+        persistent_keepalive: Some(original.persistent_keepalive),
+        ..Default::default()
+    };
+
+    // Restore pre-shared key if we know it
+    if let Some(preshared_key) = &original.preshared_key {
+        config.preshared_key = Some(decode_key(preshared_key)?);
+    }
+
+    // Restore allowed ips
+    for item in &original.allowed_ips {
+        if let Some((ip, cidr)) = item.split_once('/') {
+            if let (Ok(ip), Ok(cidr)) = (ip.parse::<IpAddr>(), cidr.parse::<u8>()) {
+                config.allowed_ips.push((ip, cidr));
             }
-            p.allowed_ips = allowed_ips;
         }
-        
-        // create a query
-        let dev = Device {
-            interface,
-            flags: vec![],
-            private_key: None,
-            listen_port: None,
-            fwmark: None,
-            peers: vec![p],
-        };
-
-        return wg.set_device(dev);
     }
-    return Ok(());
-}
\ No newline at end of file
+
+    backend.set_peer(device_name, &config)
+}
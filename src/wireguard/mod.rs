@@ -1 +1,3 @@
+pub mod backend;
+pub mod error;
 pub mod information;